@@ -0,0 +1,212 @@
+// Measures decoding speed on test-files/plrabn12.txt, for both record layouts, and compares it
+// against a reference decoder working the way the original one did.
+// Run with `cargo bench --bench decode`.
+use lzss::decoder;
+use lzss::encoder::Encoder;
+use lzss::{Config, Layout};
+use std::fs;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 50;
+
+// Speedup over the reference decoder the current one was meant to reach
+const TARGET_SPEEDUP: f64 = 10.0;
+
+// Decoder as it was before tokens were decoded into a shared output window: fields are read a
+// bit at a time, every record is collected into it's own Vec and history is a deque which gets
+// updated byte by byte. Handles only bit packed streams without preset dictionary.
+mod reference {
+    use lzss::Config;
+    use std::collections::VecDeque;
+    use std::io::{Error, ErrorKind, Read, Result, Write};
+
+    // Pulls every byte from the reader in a separate `read` call
+    struct BitReader<R: Read> {
+        reader: R,
+        byte: u8,
+        nbits: usize,
+    }
+
+    impl<R: Read> BitReader<R> {
+        fn read_bit(&mut self) -> Result<bool> {
+            if self.nbits == 0 {
+                let mut byte = [0];
+                if self.reader.read(&mut byte)? == 0 {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "End of stream"));
+                }
+                self.byte = byte[0];
+                self.nbits = 8;
+            }
+            let value = self.byte & 0x80 != 0;
+            self.byte <<= 1;
+            self.nbits -= 1;
+            Ok(value)
+        }
+
+        fn read_bits(&mut self, n: usize) -> Result<u32> {
+            let mut value = 0;
+            for _ in 0..n {
+                value = value << 1 | self.read_bit()? as u32;
+            }
+            Ok(value)
+        }
+    }
+
+    pub fn decode<R: Read, W: Write>(reader: R, config: Config, writer: &mut W) -> Result<()> {
+        let mut br = BitReader {
+            reader,
+            byte: 0,
+            nbits: 0,
+        };
+        let history_addr_nbits = br.read_bits(5)? as usize;
+        let match_length_nbits = br.read_bits(4)? as usize;
+        assert_eq!(history_addr_nbits, config.history_addr_nbits().into());
+        let history_size = config.history_size();
+        let threshold = config.threshold();
+        let mut history = VecDeque::with_capacity(history_size);
+
+        let mut write_decoded = |bytes: Vec<u8>, history: &mut VecDeque<u8>| -> Result<()> {
+            writer.write_all(&bytes)?;
+            let new_size = history.len() + bytes.len();
+            if new_size > history_size {
+                for _ in 0..new_size - history_size {
+                    history.pop_front();
+                }
+            }
+            for byte in bytes {
+                history.push_back(byte);
+            }
+            Ok(())
+        };
+
+        for _ in 0..config.current_window_size() {
+            let byte = br.read_bits(8)? as u8;
+            write_decoded(vec![byte], &mut history)?;
+        }
+        loop {
+            let bytes = match br.read_bit() {
+                Ok(true) => match br.read_bits(8) {
+                    Ok(byte) => vec![byte as u8],
+                    Err(_) => break,
+                },
+                Ok(false) => {
+                    let position = br.read_bits(history_addr_nbits)? as usize;
+                    let length = br.read_bits(match_length_nbits)? as usize + threshold;
+                    if position >= history.len() {
+                        return Err(Error::new(ErrorKind::InvalidData, "Invalid position"));
+                    }
+                    history
+                        .range(position..position + length)
+                        .copied()
+                        .collect()
+                }
+                Err(_) => break,
+            };
+            write_decoded(bytes, &mut history)?;
+        }
+        writer.flush()
+    }
+}
+
+// Decodes the test file into the given Vec
+type Decode<'a> = Box<dyn FnMut(&mut Vec<u8>) + 'a>;
+
+// A decoder being measured
+struct Candidate<'a> {
+    name: String,
+    decode: Decode<'a>,
+    elapsed: Duration,
+}
+
+impl<'a> Candidate<'a> {
+    fn new<F: FnMut(&mut Vec<u8>) + 'a>(name: &str, decode: F) -> Candidate<'a> {
+        Candidate {
+            name: name.to_string(),
+            decode: Box::new(decode),
+            elapsed: Duration::MAX,
+        }
+    }
+}
+
+// Fastest of `ITERATIONS` runs of every candidate, which is the least disturbed by the rest of
+// the system. Candidates take turns, so that slower periods of the machine affect all of them.
+fn measure(source: &[u8], candidates: &mut [Candidate]) {
+    let mut decoded = Vec::with_capacity(source.len());
+    for _ in 0..ITERATIONS {
+        for candidate in candidates.iter_mut() {
+            decoded.clear();
+            let start = Instant::now();
+            (candidate.decode)(&mut decoded);
+            candidate.elapsed = candidate.elapsed.min(start.elapsed());
+            assert!(
+                decoded == source,
+                "{} did not decode the original file",
+                candidate.name
+            );
+        }
+    }
+}
+
+fn encode(source: &[u8], config: Config) -> Vec<u8> {
+    let mut archive = Vec::new();
+    Encoder::with_config(config)
+        .encode(&mut &source[..], &mut archive)
+        .expect("Unable to encode test file");
+    archive
+}
+
+fn report(name: &str, source: &[u8], elapsed: Duration, reference: Duration) {
+    let mbps = source.len() as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0);
+    println!(
+        "{:<28} {:>12?} {:>8.1} MiB/s {:>6.1}x",
+        name,
+        elapsed,
+        mbps,
+        reference.as_secs_f64() / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    let source = fs::read("test-files/plrabn12.txt").expect("Unable to read test file");
+    let config = Config::new(12, 4, 0);
+    println!(
+        "decode plrabn12.txt ({} bytes), fastest of {} runs, speedup over reference",
+        source.len(),
+        ITERATIONS
+    );
+
+    let archive = encode(&source, config);
+    let layouts = [
+        Layout::BitPacked,
+        Layout::FlagGroups(8),
+        Layout::FlagGroups(32),
+    ];
+    let archives: Vec<_> = layouts
+        .iter()
+        .map(|&layout| encode(&source, config.with_layout(layout)))
+        .collect();
+    let mut candidates = vec![Candidate::new("reference", |decoded| {
+        reference::decode(archive.as_slice(), config, decoded).expect("Unable to decode test file")
+    })];
+    for (layout, archive) in layouts.iter().zip(&archives) {
+        candidates.push(Candidate::new(&format!("{:?}", layout), move |decoded| {
+            decoder::decode(archive.as_slice(), decoded).expect("Unable to decode test file")
+        }));
+    }
+    measure(&source, &mut candidates);
+    let reference = candidates[0].elapsed;
+    for candidate in &candidates {
+        report(&candidate.name, &source, candidate.elapsed, reference);
+    }
+    let bit_packed = candidates[1].elapsed;
+
+    let speedup = reference.as_secs_f64() / bit_packed.as_secs_f64();
+    if speedup >= TARGET_SPEEDUP {
+        println!("target of {}x speedup met", TARGET_SPEEDUP);
+    } else {
+        println!(
+            "target of {}x speedup NOT met: bit packed decoding is {:.1}x faster",
+            TARGET_SPEEDUP, speedup
+        );
+    }
+}
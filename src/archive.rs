@@ -0,0 +1,256 @@
+// Multi-file archives (.lzsa). Every file is stored as a separate stream in the format of this
+// crate, preceded by a header with it's metadata, so entries can be listed without decoding
+// them and any entry can be extracted alone. All numbers are big endian.
+//
+//   magic "LZSA", version (1 byte)
+//   entries, each of them:
+//     path length (2 bytes), path (UTF-8, relative, components separated by /)
+//     mode (4 bytes), modification time (8 bytes, seconds since Unix epoch)
+//     size (8 bytes), adler32 of uncompressed data (4 bytes)
+//     compressed size (8 bytes), compressed stream
+//   end of archive: path length 0
+use super::checksum::{checksum_mismatch, ChecksumReader, ChecksumWriter};
+use super::decoder;
+use super::encoder::Encoder;
+use super::Level;
+use std::io::{self, Error, ErrorKind, Read, Result, Write};
+
+pub const MAGIC: &[u8; 4] = b"LZSA";
+pub const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub path: String,
+    // Unix permission bits
+    pub mode: u32,
+    pub mtime: u64,
+    pub size: u64,
+    pub adler32: u32,
+    pub compressed_size: u64,
+}
+
+impl Entry {
+    fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&(self.path.len() as u16).to_be_bytes())?;
+        writer.write_all(self.path.as_bytes())?;
+        writer.write_all(&self.mode.to_be_bytes())?;
+        writer.write_all(&self.mtime.to_be_bytes())?;
+        writer.write_all(&self.size.to_be_bytes())?;
+        writer.write_all(&self.adler32.to_be_bytes())?;
+        writer.write_all(&self.compressed_size.to_be_bytes())
+    }
+
+    // Returns None at the end of archive
+    fn read<R: Read>(reader: &mut R) -> Result<Option<Entry>> {
+        let path_len = u16::from_be_bytes(read_array(reader)?);
+        if path_len == 0 {
+            return Ok(None);
+        }
+        let mut path = vec![0; usize::from(path_len)];
+        reader.read_exact(&mut path)?;
+        let path = String::from_utf8(path)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Entry path is not valid UTF-8"))?;
+        check_path(&path).map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))?;
+        Ok(Some(Entry {
+            path,
+            mode: u32::from_be_bytes(read_array(reader)?),
+            mtime: u64::from_be_bytes(read_array(reader)?),
+            size: u64::from_be_bytes(read_array(reader)?),
+            adler32: u32::from_be_bytes(read_array(reader)?),
+            compressed_size: u64::from_be_bytes(read_array(reader)?),
+        }))
+    }
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+// Entries can't be extracted outside of the destination directory
+fn check_path(path: &str) -> Result<()> {
+    let valid = !path.is_empty()
+        && path.len() <= usize::from(u16::MAX)
+        && !path.starts_with('/')
+        && path
+            .split('/')
+            .all(|component| !component.is_empty() && component != "." && component != "..");
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid entry path: {}", path),
+        ))
+    }
+}
+
+pub struct ArchiveWriter<W: Write> {
+    writer: W,
+    level: Level,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(mut writer: W, level: Level) -> Result<ArchiveWriter<W>> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        Ok(ArchiveWriter { writer, level })
+    }
+
+    // Adds an entry with data read from `reader`. Input is streamed through the encoder, but
+    // the compressed data of the entry is held in memory, since the header needs its size.
+    pub fn add<R: Read>(
+        &mut self,
+        path: &str,
+        mode: u32,
+        mtime: u64,
+        reader: &mut R,
+    ) -> Result<Entry> {
+        check_path(path)?;
+        let mut input = ChecksumReader::new(reader);
+        let mut compressed = Vec::new();
+        Encoder::with_level(self.level).encode(&mut input, &mut compressed)?;
+
+        let entry = Entry {
+            path: path.to_string(),
+            mode,
+            mtime,
+            size: input.bytes_read(),
+            adler32: input.sum(),
+            compressed_size: compressed.len() as u64,
+        };
+        entry.write(&mut self.writer)?;
+        self.writer.write_all(&compressed)?;
+        Ok(entry)
+    }
+
+    // Writes end of archive and returns the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.write_all(&0u16.to_be_bytes())?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+// Reads entries in order. Data of an entry has to be read or skipped before the next one.
+pub struct ArchiveReader<R: Read> {
+    reader: R,
+    // Compressed bytes of the current entry which were not read yet
+    remaining: u64,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    pub fn new(mut reader: R) -> Result<ArchiveReader<R>> {
+        let header: [u8; 5] = read_array(&mut reader)?;
+        if &header[..4] != MAGIC {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Invalid header: not an lzsa archive",
+            ));
+        }
+        if header[4] != VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported archive version {}", header[4]),
+            ));
+        }
+        Ok(ArchiveReader {
+            reader,
+            remaining: 0,
+        })
+    }
+
+    // Skips data of the current entry if it wasn't read. Returns None at the end of archive.
+    pub fn next_entry(&mut self) -> Result<Option<Entry>> {
+        self.skip_data()?;
+        let entry = Entry::read(&mut self.reader)?;
+        self.remaining = entry.as_ref().map_or(0, |entry| entry.compressed_size);
+        Ok(entry)
+    }
+
+    // Decodes data of `entry` (the last one returned by `next_entry`) and verifies it's size and
+    // checksum. Output is written as it is decoded, so it has to be discarded if an error is
+    // returned.
+    pub fn read_data<W: Write>(&mut self, entry: &Entry, writer: W) -> Result<()> {
+        let mut output = ChecksumWriter::new(writer);
+        let mut data = (&mut self.reader).take(self.remaining);
+        // Stream may end before it's padding
+        let result = decoder::decode(&mut data, &mut output)
+            .and_then(|_| io::copy(&mut data, &mut io::sink()));
+        self.remaining = data.limit();
+        result?;
+        if output.bytes_written() != entry.size {
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                "Decoded size does not match the entry header",
+            ))
+        } else if output.sum() != entry.adler32 {
+            Err(checksum_mismatch(
+                "Checksum of decoded data does not match the entry header",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn skip_data(&mut self) -> Result<()> {
+        let skipped = io::copy(
+            &mut (&mut self.reader).take(self.remaining),
+            &mut io::sink(),
+        )?;
+        if skipped < self.remaining {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Archive ended in the middle of an entry",
+            ));
+        }
+        self.remaining = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::checksum::is_checksum_mismatch;
+
+    #[test]
+    fn round_trip() {
+        let files: Vec<(&str, Vec<u8>)> = vec![
+            ("fields.c", std::fs::read("test-files/fields.c").unwrap()),
+            ("docs/empty", Vec::new()),
+            ("docs/xargs.1", std::fs::read("test-files/xargs.1").unwrap()),
+        ];
+        let mut writer = ArchiveWriter::new(Vec::new(), Level::L1).unwrap();
+        for (path, data) in &files {
+            writer
+                .add(path, 0o644, 1_600_000_000, &mut data.as_slice())
+                .unwrap();
+        }
+        assert!(writer.add("../escape", 0o644, 0, &mut &b""[..]).is_err());
+        let archive = writer.finish().unwrap();
+
+        // Second entry is skipped
+        let mut reader = ArchiveReader::new(archive.as_slice()).unwrap();
+        for (index, (path, data)) in files.iter().enumerate() {
+            let entry = reader.next_entry().unwrap().unwrap();
+            assert_eq!(entry.path, *path);
+            assert_eq!(entry.size, data.len() as u64);
+            if index != 1 {
+                let mut decoded = Vec::new();
+                reader.read_data(&entry, &mut decoded).unwrap();
+                assert_eq!(&decoded, data);
+            }
+        }
+        assert_eq!(reader.next_entry().unwrap(), None);
+
+        // Corrupted checksum of the first entry
+        let mut corrupted = archive.clone();
+        corrupted[5 + 2 + "fields.c".len() + 4 + 8 + 8] ^= 1;
+        let mut reader = ArchiveReader::new(corrupted.as_slice()).unwrap();
+        let entry = reader.next_entry().unwrap().unwrap();
+        let err = reader.read_data(&entry, io::sink()).unwrap_err();
+        assert!(is_checksum_mismatch(&err));
+    }
+}
@@ -0,0 +1,460 @@
+// MSB-first bit streams.
+// Bits are collected in (or served from) a 64-bit accumulator and moved to the underlying
+// reader/writer in large chunks, so individual fields never cause an I/O call.
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+pub struct BitWriter<W: Write> {
+    writer: W,
+    buffer: Vec<u8>,
+    // Pending bits are stored in the lowest `nbits` bits of `acc`
+    acc: u64,
+    nbits: usize,
+}
+
+impl<W: Write> BitWriter<W> {
+    pub fn new(writer: W) -> BitWriter<W> {
+        BitWriter {
+            writer,
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+            acc: 0,
+            nbits: 0,
+        }
+    }
+
+    // Writes the lowest `n` bits of `value`, most significant first
+    pub fn write_bits(&mut self, value: u32, n: usize) -> Result<()> {
+        assert!(n <= 32, "Cannot write more than 32 bits at once");
+        if self.nbits + n > 64 {
+            self.move_bytes()?;
+        }
+        let value = u64::from(value) & ((1u64 << n) - 1);
+        self.acc = (self.acc << n) | value;
+        self.nbits += n;
+        Ok(())
+    }
+
+    pub fn write_bit(&mut self, bit: bool) -> Result<()> {
+        self.write_bits(bit as u32, 1)
+    }
+
+    pub fn write_byte(&mut self, byte: u8) -> Result<()> {
+        self.write_bits(u32::from(byte), 8)
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.move_bytes()?;
+        if self.nbits == 0 {
+            self.buffer.extend_from_slice(bytes);
+            if self.buffer.len() >= BUFFER_SIZE {
+                self.write_buffer()?;
+            }
+        } else {
+            for byte in bytes {
+                self.write_byte(*byte)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Fills the rest of the current byte with zeros
+    pub fn pad_to_byte(&mut self) -> Result<()> {
+        let padding = (8 - self.nbits % 8) % 8;
+        self.write_bits(0, padding)
+    }
+
+    // Passes all complete bytes to the writer and flushes it.
+    // Bits of an incomplete byte stay pending.
+    pub fn flush(&mut self) -> Result<()> {
+        self.move_bytes()?;
+        self.write_buffer()?;
+        self.writer.flush()
+    }
+
+    // Moves complete bytes from the accumulator to the buffer
+    fn move_bytes(&mut self) -> Result<()> {
+        while self.nbits >= 8 {
+            self.nbits -= 8;
+            self.buffer.push((self.acc >> self.nbits) as u8);
+        }
+        self.acc &= (1u64 << self.nbits) - 1;
+        if self.buffer.len() >= BUFFER_SIZE {
+            self.write_buffer()?;
+        }
+        Ok(())
+    }
+
+    fn write_buffer(&mut self) -> Result<()> {
+        self.writer.write_all(&self.buffer)?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+pub struct BitReader<R: Read> {
+    reader: R,
+    buffer: Box<[u8]>,
+    pos: usize,
+    len: usize,
+    // Available bits are stored in the highest `nbits` bits of `acc`. Bits below them are
+    // either zero or the ones which follow in the stream.
+    acc: u64,
+    nbits: usize,
+    // Bytes moved to `acc` so far
+    bytes_loaded: u64,
+}
+
+impl<R: Read> BitReader<R> {
+    pub fn new(reader: R) -> BitReader<R> {
+        BitReader {
+            reader,
+            // Extra space lets `aligned_bytes` put bytes of the accumulator back in front of
+            // a freshly read buffer
+            buffer: vec![0; BUFFER_SIZE + 8].into_boxed_slice(),
+            pos: 0,
+            len: 0,
+            acc: 0,
+            nbits: 0,
+            bytes_loaded: 0,
+        }
+    }
+
+    // Number of bits read so far
+    pub fn bit_position(&self) -> u64 {
+        self.bytes_loaded * 8 - self.nbits as u64
+    }
+
+    // Reads `n` bits, most significant first.
+    // Returns UnexpectedEof error if stream ends before `n` bits are read.
+    #[inline]
+    pub fn read_bits(&mut self, n: usize) -> Result<u32> {
+        debug_assert!(n <= 32, "Cannot read more than 32 bits at once");
+        if self.nbits < n {
+            self.refill()?;
+            if self.nbits < n {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Stream ended in the middle of a field",
+                ));
+            }
+        }
+        if n == 0 {
+            return Ok(0);
+        }
+        let value = (self.acc >> (64 - n)) as u32;
+        self.acc <<= n;
+        self.nbits -= n;
+        Ok(value)
+    }
+
+    // Makes at least `n` bits (at most 56) available to `peek_bits`, unless the stream ends
+    // first. Returns number of available bits.
+    #[inline]
+    pub fn fill(&mut self, n: usize) -> Result<usize> {
+        debug_assert!(n <= 56, "Cannot fill more than 56 bits at once");
+        if self.nbits < n {
+            self.refill()?;
+        }
+        Ok(self.nbits)
+    }
+
+    // Next `n` (1 to 56) bits without consuming them. They have to be available (see `fill`).
+    #[inline]
+    pub fn peek_bits(&self, n: usize) -> u64 {
+        debug_assert!(n > 0 && n <= self.nbits, "Bits are not available");
+        self.acc >> (64 - n)
+    }
+
+    // Skips `n` available bits
+    #[inline]
+    pub fn consume(&mut self, n: usize) {
+        debug_assert!(n <= self.nbits, "Bits are not available");
+        self.acc <<= n;
+        self.nbits -= n;
+    }
+
+    // Decodes fields in a tight loop, with the accumulator kept in local variables. `decode`
+    // gets the next 56 bits of the stream at the top of a u64 and returns how many of them it
+    // used, or None to stop, in which case true is returned. Once fewer than 56 bits are left
+    // false is returned, so that the rest is read with the other methods.
+    #[inline]
+    pub fn decode_bits<F>(&mut self, mut decode: F) -> Result<bool>
+    where
+        F: FnMut(u64) -> Result<Option<usize>>,
+    {
+        let (mut acc, mut nbits, mut pos) = (self.acc, self.nbits, self.pos);
+        let result = loop {
+            if nbits < 56 {
+                if pos + 8 > self.len {
+                    self.acc = acc;
+                    self.nbits = nbits;
+                    self.bytes_loaded += (pos - self.pos) as u64;
+                    self.pos = pos;
+                    self.refill()?;
+                    if self.nbits < 56 {
+                        return Ok(false);
+                    }
+                    acc = self.acc;
+                    nbits = self.nbits;
+                    pos = self.pos;
+                } else {
+                    // Same as the fast path of `refill`
+                    let mut word = [0; 8];
+                    word.copy_from_slice(&self.buffer[pos..pos + 8]);
+                    acc |= u64::from_be_bytes(word) >> nbits;
+                    pos += (63 - nbits) / 8;
+                    nbits |= 56;
+                }
+            }
+            match decode(acc) {
+                Ok(Some(used)) => {
+                    debug_assert!(used <= 56);
+                    acc <<= used;
+                    nbits -= used;
+                }
+                Ok(None) => break Ok(true),
+                Err(error) => break Err(error),
+            }
+        };
+        self.acc = acc;
+        self.nbits = nbits;
+        self.bytes_loaded += (pos - self.pos) as u64;
+        self.pos = pos;
+        result
+    }
+
+    // Byte aligned access to the stream. Returns the unread bytes which are buffered, reading
+    // more if there are fewer than `min` (at most `BUFFER_SIZE`). Fewer are returned only if
+    // the stream ends. Stream has to be at a byte boundary. Use `consume_bytes` to skip them.
+    #[inline]
+    pub fn aligned_bytes(&mut self, min: usize) -> Result<&[u8]> {
+        if self.nbits > 0 || self.len - self.pos < min {
+            self.fill_aligned(min)?;
+        }
+        Ok(&self.buffer[self.pos..self.len])
+    }
+
+    // Skips `n` bytes returned by `aligned_bytes`
+    #[inline]
+    pub fn consume_bytes(&mut self, n: usize) {
+        debug_assert!(self.nbits == 0 && self.pos + n <= self.len);
+        self.pos += n;
+        self.bytes_loaded += n as u64;
+        // Drop bits left behind by the last refill, they are no longer the next ones
+        self.acc = 0;
+    }
+
+    pub fn read_bit(&mut self) -> Result<bool> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    pub fn read_byte(&mut self) -> Result<u8> {
+        Ok(self.read_bits(8)? as u8)
+    }
+
+    // Tops up accumulator with whole bytes for as long as it has space for them
+    #[inline]
+    fn refill(&mut self) -> Result<()> {
+        if self.pos + 8 <= self.len {
+            // Fast path: take as many bytes as fit from a single 8 byte load
+            let mut word = [0; 8];
+            word.copy_from_slice(&self.buffer[self.pos..self.pos + 8]);
+            // Bits past the whole bytes taken are the ones which follow in the stream, so
+            // later refills put the same ones there
+            self.acc |= u64::from_be_bytes(word) >> self.nbits;
+            let nbytes = (63 - self.nbits) / 8;
+            self.pos += nbytes;
+            self.nbits |= 56;
+            self.bytes_loaded += nbytes as u64;
+            return Ok(());
+        }
+        self.refill_bytewise()
+    }
+
+    // Slow path of `refill`, near the end of the buffer
+    #[cold]
+    fn refill_bytewise(&mut self) -> Result<()> {
+        while self.nbits <= 56 {
+            if self.pos == self.len && !self.fill_buffer()? {
+                break;
+            }
+            self.acc |= u64::from(self.buffer[self.pos]) << (56 - self.nbits);
+            self.pos += 1;
+            self.nbits += 8;
+            self.bytes_loaded += 1;
+        }
+        Ok(())
+    }
+
+    // Slow path of `aligned_bytes`
+    #[cold]
+    fn fill_aligned(&mut self, min: usize) -> Result<()> {
+        debug_assert!(
+            self.nbits.is_multiple_of(8),
+            "Stream is not at a byte boundary"
+        );
+        if self.nbits > 0 {
+            self.unload_acc();
+        }
+        if self.len - self.pos < min {
+            self.buffer.copy_within(self.pos..self.len, 0);
+            self.len -= self.pos;
+            self.pos = 0;
+            while self.len < min {
+                match self.reader.read(&mut self.buffer[self.len..BUFFER_SIZE]) {
+                    Ok(0) => break,
+                    Ok(n) => self.len += n,
+                    Err(ref error) if error.kind() == ErrorKind::Interrupted => {}
+                    Err(error) => return Err(error),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Puts whole bytes held in the accumulator back to the buffer
+    fn unload_acc(&mut self) {
+        let nbytes = self.nbits / 8;
+        if nbytes > self.pos {
+            // Some of them came from the previous buffer, make room in front of the current one
+            self.buffer.copy_within(self.pos..self.len, nbytes);
+            self.len += nbytes - self.pos;
+            self.pos = nbytes;
+            self.buffer[..nbytes].copy_from_slice(&self.acc.to_be_bytes()[..nbytes]);
+        }
+        self.pos -= nbytes;
+        self.bytes_loaded -= nbytes as u64;
+        self.acc = 0;
+        self.nbits = 0;
+    }
+
+    // Returns false if underlying reader has ended
+    fn fill_buffer(&mut self) -> Result<bool> {
+        loop {
+            match self.reader.read(&mut self.buffer[..BUFFER_SIZE]) {
+                Ok(n) => {
+                    self.pos = 0;
+                    self.len = n;
+                    return Ok(n > 0);
+                }
+                Err(error) => match error.kind() {
+                    ErrorKind::Interrupted => continue,
+                    _ => return Err(error),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let fields: Vec<(u32, usize)> = (0..1000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761), (i as usize % 32) + 1))
+            .collect();
+        let mut stream = Vec::new();
+        {
+            let mut bw = BitWriter::new(&mut stream);
+            for (value, n) in &fields {
+                bw.write_bits(*value, *n).unwrap();
+            }
+            bw.write_bytes(b"raw").unwrap();
+            bw.pad_to_byte().unwrap();
+            bw.flush().unwrap();
+        }
+
+        let mut br = BitReader::new(stream.as_slice());
+        let mut position = 0;
+        for (value, n) in &fields {
+            let mask = ((1u64 << n) - 1) as u32;
+            assert_eq!(br.read_bits(*n).unwrap(), value & mask);
+            position += *n as u64;
+            assert_eq!(br.bit_position(), position);
+        }
+        for byte in b"raw" {
+            assert_eq!(br.read_byte().unwrap(), *byte);
+        }
+        br.read_bits(br.nbits % 8).unwrap();
+        assert_eq!(br.bit_position(), stream.len() as u64 * 8);
+        let error = br.read_bit().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn aligned_bytes_follow_read_bits() {
+        // Returns few bytes at a time, so that bytes in the accumulator span several reads
+        struct Chunks<'a>(&'a [u8]);
+        impl Read for Chunks<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+                let n = usize::min(usize::min(3, buf.len()), self.0.len());
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+
+        let stream: Vec<u8> = (0..255).collect();
+        let mut br = BitReader::new(Chunks(&stream));
+        let mut position = 0;
+        while position < stream.len() {
+            let expected = (u32::from(stream[position]) << 8) | u32::from(stream[position + 1]);
+            assert_eq!(br.read_bits(16).unwrap(), expected);
+            position += 2;
+            let bytes = br.aligned_bytes(5).unwrap();
+            assert!(bytes.len() >= usize::min(5, stream.len() - position));
+            assert_eq!(bytes, &stream[position..position + bytes.len()]);
+            let n = usize::min(bytes.len(), 3);
+            br.consume_bytes(n);
+            position += n;
+            assert_eq!(br.bit_position(), position as u64 * 8);
+        }
+        assert!(br.aligned_bytes(1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn decode_bits_follow_read_bits() {
+        let stream: Vec<u8> = (0..1000u32).map(|i| (i * 37 % 256) as u8).collect();
+        let mut expected = BitReader::new(&stream[..]);
+        let mut br = BitReader::new(&stream[..]);
+        loop {
+            assert_eq!(br.read_bits(3).unwrap(), expected.read_bits(3).unwrap());
+            let mut fields = 0;
+            let stopped = br
+                .decode_bits(|bits| {
+                    if fields == 10 {
+                        return Ok(None);
+                    }
+                    fields += 1;
+                    assert_eq!((bits >> (64 - 13)) as u32, expected.read_bits(13).unwrap());
+                    Ok(Some(13))
+                })
+                .unwrap();
+            if !stopped {
+                break;
+            }
+        }
+        assert_eq!(br.bit_position(), expected.bit_position());
+        while let Ok(bits) = expected.read_bits(13) {
+            assert_eq!(br.read_bits(13).unwrap(), bits);
+        }
+        assert!(br.read_bits(13).is_err());
+    }
+
+    #[test]
+    fn bit_order_is_msb_first() {
+        let mut stream = Vec::new();
+        {
+            let mut bw = BitWriter::new(&mut stream);
+            bw.write_bit(true).unwrap();
+            bw.write_bits(0b0110, 4).unwrap();
+            bw.write_byte(0xAB).unwrap();
+            bw.pad_to_byte().unwrap();
+            bw.flush().unwrap();
+        }
+        assert_eq!(stream, [0b1011_0101, 0b0101_1000]);
+    }
+}
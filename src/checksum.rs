@@ -0,0 +1,159 @@
+// Checksums used by container formats and for identifying preset dictionaries
+use std::error;
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+// Largest prime smaller than 2^16
+const ADLER_MODULO: u32 = 65521;
+// Number of bytes which can be summed before `b` may overflow
+const ADLER_BLOCK_SIZE: usize = 5552;
+
+// Running adler32 checksum
+#[derive(Debug, Clone, Copy)]
+pub struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Adler32 {
+    pub fn new() -> Adler32 {
+        Adler32 { a: 1, b: 0 }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for block in bytes.chunks(ADLER_BLOCK_SIZE) {
+            for byte in block {
+                self.a += u32::from(*byte);
+                self.b += self.a;
+            }
+            self.a %= ADLER_MODULO;
+            self.b %= ADLER_MODULO;
+        }
+    }
+
+    pub fn sum(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
+impl Default for Adler32 {
+    fn default() -> Adler32 {
+        Adler32::new()
+    }
+}
+
+pub fn adler32(bytes: &[u8]) -> u32 {
+    let mut adler = Adler32::new();
+    adler.update(bytes);
+    adler.sum()
+}
+
+// Payload of InvalidData errors for data which doesn't match it's checksum, so callers can tell
+// them from other kinds of corrupt data
+#[derive(Debug)]
+pub struct ChecksumMismatch(String);
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl error::Error for ChecksumMismatch {}
+
+pub fn checksum_mismatch(message: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        ChecksumMismatch(message.to_string()),
+    )
+}
+
+pub fn is_checksum_mismatch(err: &Error) -> bool {
+    err.get_ref()
+        .is_some_and(|inner| inner.is::<ChecksumMismatch>())
+}
+
+// Passes data through while checksumming it
+pub struct ChecksumWriter<W: Write> {
+    writer: W,
+    adler: Adler32,
+    bytes_written: u64,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub fn new(writer: W) -> ChecksumWriter<W> {
+        ChecksumWriter {
+            writer,
+            adler: Adler32::new(),
+            bytes_written: 0,
+        }
+    }
+
+    // Adler32 of everything written so far
+    pub fn sum(&self) -> u32 {
+        self.adler.sum()
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.adler.update(&buf[..n]);
+        self.bytes_written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Passes data through while checksumming it
+pub struct ChecksumReader<R: Read> {
+    reader: R,
+    adler: Adler32,
+    bytes_read: u64,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    pub fn new(reader: R) -> ChecksumReader<R> {
+        ChecksumReader {
+            reader,
+            adler: Adler32::new(),
+            bytes_read: 0,
+        }
+    }
+
+    // Adler32 of everything read so far
+    pub fn sum(&self) -> u32 {
+        self.adler.sum()
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.adler.update(&buf[..n]);
+        self.bytes_read += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn adler32_of_known_input() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(adler32(&[]), 1);
+    }
+}
@@ -0,0 +1,1081 @@
+// Serialization of tokens.
+// A codec decides how flags, literals and references are laid out in a stream (flag grouping,
+// bit order, field widths, what positions are relative to), while `Encoder` and `Decoder` only
+// deal with tokens. Supporting another LZSS dialect means implementing this trait.
+use super::bit_io::{BitReader, BitWriter};
+use super::checksum::adler32;
+use super::output_window::{copy_match_within, COPY_SLACK};
+use super::*;
+use log::debug;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+pub trait RecordCodec {
+    // Maximum number of bytes references can reach back
+    fn history_size(&self) -> usize;
+
+    // Shortest match which can be encoded as a reference
+    fn min_match_length(&self) -> usize;
+
+    // Longest match which can be encoded as a reference
+    fn max_match_length(&self) -> usize;
+
+    // Number of bytes at the beginning of a stream which are always encoded as literals
+    fn literal_prefix_len(&self) -> usize {
+        0
+    }
+
+    // Bytes history is filled with before a stream starts. References can point into them.
+    fn preset_history(&self) -> &[u8] {
+        &[]
+    }
+
+    // Called before `write_header` with length of the whole input if it is shorter than
+    // `literal_prefix_len`, None otherwise
+    fn set_short_input(&mut self, _len: Option<usize>) {}
+
+    fn write_header<W: Write>(&mut self, _bw: &mut BitWriter<W>) -> Result<()> {
+        Ok(())
+    }
+
+    // Positions of matches are indexes into current history (last `history_size` bytes)
+    fn write_token<W: Write>(&mut self, bw: &mut BitWriter<W>, token: Token) -> Result<()>;
+
+    // Writes the first `bytes` of a stream, no more than `literal_prefix_len`.
+    // Formats which store the prefix unencoded can write it in one go.
+    fn write_prefix<W: Write>(&mut self, bw: &mut BitWriter<W>, bytes: &[u8]) -> Result<()> {
+        for byte in bytes {
+            self.write_token(bw, Token::Literal(*byte))?;
+        }
+        Ok(())
+    }
+
+    // Writes whatever is needed for decoder to recognize the end of a stream
+    fn finish<W: Write>(&mut self, bw: &mut BitWriter<W>) -> Result<()>;
+
+    // Returns None if stream has ended.
+    // Has to return InvalidData error for matches which do not fit in current history.
+    fn read_token<R: Read>(&mut self, br: &mut BitReader<R>) -> Result<Option<Token>>;
+
+    // Decodes records straight into `buffer`, after the bytes in buffer[0..*end] (which hold at
+    // least the current history) and moves `end` past them. Stops with true once fewer than
+    // `max_match_length() + 16` bytes of room are left, or with false when the stream ends.
+    // Formats can override it with a loop which does not go through tokens.
+    fn decode_into<R: Read>(
+        &mut self,
+        br: &mut BitReader<R>,
+        buffer: &mut [u8],
+        end: &mut usize,
+    ) -> Result<bool> {
+        decode_tokens(self, br, buffer, end)
+    }
+}
+
+// Default `decode_into`, which gets records one token at a time
+fn decode_tokens<C: RecordCodec + ?Sized, R: Read>(
+    codec: &mut C,
+    br: &mut BitReader<R>,
+    buffer: &mut [u8],
+    end: &mut usize,
+) -> Result<bool> {
+    let limit = buffer.len() - codec.max_match_length() - COPY_SLACK;
+    while *end <= limit {
+        match codec.read_token(br)? {
+            Some(token) => append_token(buffer, end, codec.history_size(), token)?,
+            None => return Ok(false),
+        }
+    }
+    Ok(true)
+}
+
+#[inline]
+fn append_token(
+    buffer: &mut [u8],
+    end: &mut usize,
+    history_size: usize,
+    token: Token,
+) -> Result<()> {
+    match token {
+        Token::Literal(byte) => {
+            buffer[*end] = byte;
+            *end += 1;
+        }
+        Token::Match { position, length } => {
+            let history_len = usize::min(*end, history_size);
+            if position >= history_len {
+                return Err(invalid_position());
+            }
+            copy_match_within(buffer, *end - history_len + position, *end, length);
+            *end += length;
+        }
+    }
+    Ok(())
+}
+
+fn invalid_position() -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        "Invalid record: position bigger than current history size",
+    )
+}
+
+// Extended header flag of streams encoded with a preset dictionary
+const HEADER_FLAG_DICTIONARY: u8 = 1;
+// Extended header flag of streams whose input is shorter than the unencoded beginning
+const HEADER_FLAG_SHORT_INPUT: u8 = 2;
+
+// Format of this crate: header with history address and match length sizes, unencoded
+// beginning of a stream and then records, each starting with a type bit. Fields are written
+// MSB first, with no byte alignment. Reference positions are indexes into history.
+//
+// Streams with other layouts than `Layout::BitPacked` start with an extended header instead.
+// It's first 5 bits are 0 (an invalid history address size), followed by 3 zero bits and
+// bytes with history address size, match length size, layout (0 - bit packed, 8/16/32 - flag
+// count of a control word) and flags. Flag `HEADER_FLAG_DICTIONARY` means the stream was
+// encoded with a preset dictionary, whose ID (adler32 of it's contents) follows in 4 bytes.
+// With a dictionary history starts filled with it and there is no unencoded beginning.
+// Flag `HEADER_FLAG_SHORT_INPUT` means the whole input is shorter than the unencoded
+// beginning. It's length follows in 4 bytes and the stream ends right after the input. A stream
+// without it which ends within the unencoded beginning is truncated.
+// With flag groups a reference is position and length - threshold packed into as few whole
+// bytes as possible, most significant byte first.
+pub struct LzssCodec {
+    config: Config,
+    // Preset dictionary, or only it's ID until it is supplied when decoding
+    dictionary: Vec<u8>,
+    dictionary_id: Option<u32>,
+    literal_prefix_len: usize,
+    // Length of an input shorter than the unencoded beginning
+    short_input_len: Option<usize>,
+    history_addr_nbits: usize,
+    match_length_nbits: usize,
+    history_size: usize,
+    threshold: usize,
+    current_window_size: usize,
+    // Size of a reference with flag groups
+    reference_nbytes: usize,
+    // Bits which hold any bit packed record
+    record_peek_nbits: usize,
+    // Size of a control word with flag groups
+    control_word_nbytes: usize,
+    flags_out: FlagGroupWriter,
+    flags_in: FlagGroupReader,
+    bits_written: usize,
+    // Number of bytes tokens written or read so far expand to
+    bytes_processed: usize,
+}
+
+// Fields of a stream header
+struct Header {
+    history_addr_nbits: usize,
+    match_length_nbits: usize,
+    layout: Layout,
+    dictionary_id: Option<u32>,
+    short_input_len: Option<usize>,
+}
+
+impl LzssCodec {
+    pub fn new(config: Config) -> LzssCodec {
+        let history_addr_nbits = config.history_addr_nbits() as usize;
+        let match_length_nbits = config.match_length_nbits() as usize;
+        // Unused with bit packed records
+        let flag_count = match config.layout() {
+            Layout::BitPacked => 8,
+            Layout::FlagGroups(flag_count) => flag_count as usize,
+        };
+        LzssCodec {
+            config,
+            dictionary: Vec::new(),
+            dictionary_id: None,
+            literal_prefix_len: config.current_window_size(),
+            short_input_len: None,
+            history_addr_nbits,
+            match_length_nbits,
+            history_size: config.history_size(),
+            threshold: config.threshold(),
+            current_window_size: config.current_window_size(),
+            reference_nbytes: (history_addr_nbits + match_length_nbits).div_ceil(8),
+            record_peek_nbits: usize::max(1 + 8, 1 + history_addr_nbits + match_length_nbits),
+            control_word_nbytes: flag_count / 8,
+            flags_out: FlagGroupWriter::new(flag_count, BitOrder::MsbFirst),
+            flags_in: FlagGroupReader::new(flag_count, BitOrder::MsbFirst),
+            bits_written: 0,
+            bytes_processed: 0,
+        }
+    }
+
+    // Codec for a stream which starts with `dictionary` in history
+    pub fn with_dictionary(mut self, dictionary: &[u8]) -> LzssCodec {
+        let dictionary = &dictionary[dictionary.len().saturating_sub(self.history_size)..];
+        self.dictionary = dictionary.to_vec();
+        self.dictionary_id = Some(adler32(dictionary));
+        self.literal_prefix_len = 0;
+        self
+    }
+
+    // ID of the dictionary stream was (or will be) encoded with, if it uses one
+    pub fn dictionary_id(&self) -> Option<u32> {
+        self.dictionary_id
+    }
+
+    // Supplies the dictionary a stream being decoded needs. Checks that it is the right one.
+    pub fn load_dictionary(&mut self, dictionary: &[u8]) -> Result<()> {
+        let dictionary = &dictionary[dictionary.len().saturating_sub(self.history_size)..];
+        match self.dictionary_id {
+            None => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Stream was encoded without a preset dictionary",
+            )),
+            Some(id) if id != adler32(dictionary) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Stream was encoded with a different preset dictionary",
+            )),
+            Some(_) => {
+                self.dictionary = dictionary.to_vec();
+                Ok(())
+            }
+        }
+    }
+
+    // Reads header of a stream and creates a codec for reading the rest of it.
+    // If stream was encoded with a dictionary, it has to be loaded before reading records.
+    pub fn read_header<R: Read>(br: &mut BitReader<R>) -> Result<LzssCodec> {
+        let res = LzssCodec::read_header_fields(br);
+        let header = match res {
+            Ok(header) => header,
+            Err(err) => {
+                return Err(Error::new(
+                    err.kind(),
+                    format!("Error while reading header: {}", err),
+                ))
+            }
+        };
+        let history_addr_nbits = header.history_addr_nbits;
+        let match_length_nbits = header.match_length_nbits;
+        debug!(
+            "Header: ({}, {}, {:?})",
+            history_addr_nbits, match_length_nbits, header.layout
+        );
+        if !(MIN_HISTORY_ADDR_BITS..=MAX_HISTORY_ADDR_BITS).contains(&history_addr_nbits)
+            || !(MIN_MATCH_LENGTH_BITS..=MAX_MATCH_LENGTH_BITS).contains(&match_length_nbits)
+            || history_addr_nbits <= match_length_nbits
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Invalid header: unsupported history or match length size",
+            ));
+        }
+
+        let config = Config::new(history_addr_nbits as u8, match_length_nbits as u8, 0)
+            .with_layout(header.layout);
+        let mut codec = LzssCodec::new(config);
+        if header.dictionary_id.is_some() {
+            codec.dictionary_id = header.dictionary_id;
+            codec.literal_prefix_len = 0;
+        }
+        if let Some(len) = header.short_input_len {
+            if len >= codec.literal_prefix_len {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Invalid header: input length does not fit in the unencoded beginning",
+                ));
+            }
+            codec.literal_prefix_len = len;
+            codec.short_input_len = Some(len);
+        }
+        Ok(codec)
+    }
+
+    fn read_header_fields<R: Read>(br: &mut BitReader<R>) -> Result<Header> {
+        let history_nbits = br.read_bits(BITS_FOR_HISTORY_ADDR_NBTIS)? as usize;
+        if history_nbits != 0 {
+            let match_len_nbits = br.read_bits(BITS_FOR_MATCH_LENGTH_NBITS)? as usize;
+            return Ok(Header {
+                history_addr_nbits: history_nbits,
+                match_length_nbits: match_len_nbits,
+                layout: Layout::BitPacked,
+                dictionary_id: None,
+                short_input_len: None,
+            });
+        }
+
+        // Extended header
+        let invalid = |message| Err(Error::new(ErrorKind::InvalidData, message));
+        if br.read_bits(3)? != 0 {
+            return invalid("Invalid header: unsupported header version");
+        }
+        let history_nbits = br.read_byte()? as usize;
+        let match_len_nbits = br.read_byte()? as usize;
+        let layout = match br.read_byte()? {
+            0 => Layout::BitPacked,
+            count @ 8 | count @ 16 | count @ 32 => Layout::FlagGroups(count),
+            _ => return invalid("Invalid header: unknown layout"),
+        };
+        let flags = br.read_byte()?;
+        if flags & !(HEADER_FLAG_DICTIONARY | HEADER_FLAG_SHORT_INPUT) != 0 {
+            return invalid("Invalid header: unknown flags");
+        }
+        let dictionary_id = if flags & HEADER_FLAG_DICTIONARY != 0 {
+            Some(br.read_bits(32)?)
+        } else {
+            None
+        };
+        let short_input_len = if flags & HEADER_FLAG_SHORT_INPUT != 0 {
+            Some(br.read_bits(32)? as usize)
+        } else {
+            None
+        };
+        Ok(Header {
+            history_addr_nbits: history_nbits,
+            match_length_nbits: match_len_nbits,
+            layout,
+            dictionary_id,
+            short_input_len,
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn write_reference_record<W: Write>(
+        &mut self,
+        bw: &mut BitWriter<W>,
+        pos: usize,
+        length: usize,
+    ) -> Result<()> {
+        bw.write_bit(RECORD_TYPE_REFERENCE)?;
+        // Downcasting. But we limit possible positions (and lengths) in the beginning (when creating Decoder).
+        bw.write_bits(pos as u32, self.history_addr_nbits)?;
+        // Not encoding with this type of record if it's shorter match than threshold
+        let enc_len = (length - self.threshold) as u32;
+        bw.write_bits(enc_len, self.match_length_nbits)?;
+
+        self.bits_written += 1 + self.history_addr_nbits + self.match_length_nbits;
+
+        debug!(
+            "Record: Reference {{ position: {}, length: {} }}",
+            pos, length
+        );
+
+        Ok(())
+    }
+
+    fn write_literal_record<W: Write>(&mut self, bw: &mut BitWriter<W>, byte: u8) -> Result<()> {
+        bw.write_bit(RECORD_TYPE_LITERAL)?;
+        bw.write_byte(byte)?;
+
+        self.bits_written += 1 + 8;
+
+        debug!("Record: Literal {{ byte: {} }}", byte);
+        Ok(())
+    }
+
+    // Returns None if file ends
+    // Else the next Record if or an error
+    #[allow(clippy::match_bool)]
+    #[inline]
+    fn read_next_record<R: Read>(&mut self, br: &mut BitReader<R>) -> Result<Option<Token>> {
+        // Fast path: whole record is available, so fields are taken from one peek
+        let nbits = self.record_peek_nbits;
+        if br.fill(nbits)? >= nbits {
+            let bits = br.peek_bits(nbits);
+            let token = if (bits >> (nbits - 1)) as u8 == RECORD_TYPE_LITERAL as u8 {
+                br.consume(9);
+                Token::Literal((bits >> (nbits - 9)) as u8)
+            } else {
+                let reference_nbits = 1 + self.history_addr_nbits + self.match_length_nbits;
+                br.consume(reference_nbits);
+                let fields = bits >> (nbits - reference_nbits);
+                let length_mask = (1 << self.match_length_nbits) - 1;
+                let position = (fields >> self.match_length_nbits) as usize;
+                let length = (fields & length_mask) as usize + self.threshold;
+                self.check_reference(position, length)?
+            };
+            return Ok(Some(token));
+        }
+
+        // There are two valid ways for an archive file to end:
+        //  1. At the byte boundary (if the end of the last record is at the byte boundary)
+        //  2. Or if last record does not end at byte boundary,
+        //  it has to end with a 1 and padding till the next byte boundary
+        //  (which creates an invalid literal record - that's how we know it's the end).
+        // If we get EOF when reading type bit, it's the first type of ending.
+        // If we get a literal type bit and EOF while reading it's byte, it means it's the second type of ending.
+        // Every other case of EOF is interpreted as InvalidData error.
+
+        match br.read_bit() {
+            Ok(rec_type_bit) => match rec_type_bit {
+                RECORD_TYPE_LITERAL => self.read_literal(br),
+                RECORD_TYPE_REFERENCE => self.read_reference(br),
+            },
+            Err(error) => match error.kind() {
+                ErrorKind::UnexpectedEof => Ok(None),
+                _ => Err(Error::new(
+                    error.kind(),
+                    format!("Error reading a record type bit: {}", error),
+                )),
+            },
+        }
+    }
+
+    fn read_literal<R: Read>(&mut self, br: &mut BitReader<R>) -> Result<Option<Token>> {
+        match br.read_byte() {
+            Ok(byte) => Ok(Some(Token::Literal(byte))),
+            Err(error) => match error.kind() {
+                ErrorKind::UnexpectedEof => Ok(None),
+                _ => Err(Error::new(
+                    error.kind(),
+                    format!("Error while reading a literal record: {}", error),
+                )),
+            },
+        }
+    }
+
+    fn read_reference<R: Read>(&mut self, br: &mut BitReader<R>) -> Result<Option<Token>> {
+        let handle_error = |res: Result<u32>| match res {
+            Err(error) => Err(Error::new(
+                error.kind(),
+                format!("Error while reading a reference record: {}", error),
+            )),
+            Ok(r) => Ok(r),
+        };
+        let position = handle_error(br.read_bits(self.history_addr_nbits))? as usize;
+        let length = handle_error(br.read_bits(self.match_length_nbits))? as usize + self.threshold;
+        self.check_reference(position, length).map(Some)
+    }
+
+    #[inline]
+    fn check_reference(&self, position: usize, length: usize) -> Result<Token> {
+        if position
+            >= usize::min(
+                self.dictionary.len() + self.bytes_processed,
+                self.history_size,
+            )
+        {
+            return Err(invalid_position());
+        } else if length > self.current_window_size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Invalid record: length bigger than possible current window size",
+            ));
+        }
+
+        Ok(Token::Match { position, length })
+    }
+
+    // `decode_into` for bit packed records: each is taken from a single peek and written to the
+    // buffer right away
+    fn decode_records<R: Read>(
+        &mut self,
+        br: &mut BitReader<R>,
+        buffer: &mut [u8],
+        end: &mut usize,
+    ) -> Result<bool> {
+        let reference_nbits = 1 + self.history_addr_nbits + self.match_length_nbits;
+        let match_length_nbits = self.match_length_nbits;
+        let length_mask = (1 << match_length_nbits) - 1;
+        let threshold = self.threshold;
+        let history_size = self.history_size;
+        let limit = buffer.len() - self.current_window_size - COPY_SLACK;
+        let mut pos = *end;
+        // Record type and literal byte are at fixed places at the top of the bits
+        let stopped = br.decode_bits(|bits| {
+            if pos > limit {
+                return Ok(None);
+            }
+            if (bits >> 63) as u8 == RECORD_TYPE_LITERAL as u8 {
+                buffer[pos] = (bits >> 55) as u8;
+                pos += 1;
+                Ok(Some(9))
+            } else {
+                let fields = bits >> (64 - reference_nbits);
+                let position = (fields >> match_length_nbits) as usize;
+                let length = (fields & length_mask) as usize + threshold;
+                // Buffer holds exactly the history until it fills up the first time
+                let history_len = usize::min(pos, history_size);
+                if position >= history_len {
+                    return Err(invalid_position());
+                }
+                copy_match_within(buffer, pos - history_len + position, pos, length);
+                pos += length;
+                Ok(Some(reference_nbits))
+            }
+        });
+        self.bytes_processed += pos - *end;
+        *end = pos;
+        if stopped? {
+            return Ok(true);
+        }
+
+        // Last records of the stream
+        while *end <= limit {
+            match self.read_next_record(br)? {
+                Some(token) => {
+                    append_token(buffer, end, history_size, token)?;
+                    self.bytes_processed += token.decoded_len();
+                }
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    // `decode_into` for flag groups: records of a whole control word are taken straight from
+    // the bytes of the stream. Fields are loaded 8 bytes at a time, which may reach past the
+    // group.
+    fn decode_groups<R: Read>(
+        &mut self,
+        br: &mut BitReader<R>,
+        buffer: &mut [u8],
+        end: &mut usize,
+    ) -> Result<bool> {
+        let flag_count = self.control_word_nbytes * 8;
+        let reference_nbytes = self.reference_nbytes;
+        let match_length_nbits = self.match_length_nbits;
+        let length_mask = (1 << match_length_nbits) - 1;
+        let threshold = self.threshold;
+        let history_size = self.history_size;
+        let nbytes = self.control_word_nbytes + flag_count * reference_nbytes + 8;
+        let limit = buffer.len() - self.current_window_size - COPY_SLACK;
+        while *end <= limit {
+            let bytes = br.aligned_bytes(nbytes)?;
+            if !self.flags_in.group_ended() || bytes.len() < nbytes {
+                // Rest of a group left by the previous call, or the last groups of the stream
+                match self.read_grouped_record(br)? {
+                    Some(token) => {
+                        append_token(buffer, end, history_size, token)?;
+                        self.bytes_processed += token.decoded_len();
+                        continue;
+                    }
+                    None => return Ok(false),
+                }
+            }
+
+            let word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            let mut flags = word >> (32 - flag_count) << (32 - flag_count);
+            let mut used = self.control_word_nbytes;
+            let mut pos = *end;
+            for taken in 0..flag_count {
+                if pos > limit {
+                    // Leave the rest of the group for the next call
+                    self.flags_in.start_group(word >> (32 - flag_count));
+                    for _ in 0..taken {
+                        self.flags_in.take_flag();
+                    }
+                    break;
+                }
+                if (flags >> 31) as u8 == RECORD_TYPE_LITERAL as u8 {
+                    buffer[pos] = bytes[used];
+                    used += 1;
+                    pos += 1;
+                } else {
+                    let mut word = [0; 8];
+                    word.copy_from_slice(&bytes[used..used + 8]);
+                    let fields = u64::from_be_bytes(word) >> (64 - reference_nbytes * 8);
+                    used += reference_nbytes;
+                    let position = (fields >> match_length_nbits) as usize;
+                    let length = (fields & length_mask) as usize + threshold;
+                    let history_len = usize::min(pos, history_size);
+                    if position >= history_len {
+                        return Err(invalid_position());
+                    }
+                    copy_match_within(buffer, pos - history_len + position, pos, length);
+                    pos += length;
+                }
+                flags <<= 1;
+            }
+            br.consume_bytes(used);
+            self.bytes_processed += pos - *end;
+            *end = pos;
+        }
+        Ok(true)
+    }
+
+    fn read_prefix_byte<R: Read>(&mut self, br: &mut BitReader<R>) -> Result<u8> {
+        br.read_byte().map_err(|err| {
+            Error::new(
+                err.kind(),
+                format!("Error while reading beginning of a file: {}", err),
+            )
+        })
+    }
+
+    // Reads a record of the flag group layout.
+    // Stream can end where a record starts, unused flags of the last control word are ignored.
+    #[inline]
+    fn read_grouped_record<R: Read>(&mut self, br: &mut BitReader<R>) -> Result<Option<Token>> {
+        // Fast path: control word and record are buffered, so they are taken straight from
+        // the bytes of the stream. Fields are loaded 8 bytes at a time, which may reach past the
+        // record.
+        let nbytes = self.control_word_nbytes + 8;
+        let bytes = br.aligned_bytes(nbytes)?;
+        if bytes.len() >= nbytes {
+            let mut used = 0;
+            if self.flags_in.group_ended() {
+                let flags = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                self.flags_in
+                    .start_group(flags >> (32 - self.control_word_nbytes * 8));
+                used = self.control_word_nbytes;
+            }
+            let token = if self.flags_in.take_flag() == RECORD_TYPE_LITERAL {
+                used += 1;
+                Token::Literal(bytes[used - 1])
+            } else {
+                let mut word = [0; 8];
+                word.copy_from_slice(&bytes[used..used + 8]);
+                let fields = u64::from_be_bytes(word) >> (64 - self.reference_nbytes * 8);
+                used += self.reference_nbytes;
+                let length_mask = (1 << self.match_length_nbits) - 1;
+                let position = (fields >> self.match_length_nbits) as usize;
+                let length = (fields & length_mask) as usize + self.threshold;
+                self.check_reference(position, length)?
+            };
+            br.consume_bytes(used);
+            return Ok(Some(token));
+        }
+
+        match self.flags_in.next_flag(br)? {
+            None => Ok(None),
+            Some(RECORD_TYPE_LITERAL) => self.read_literal(br),
+            Some(RECORD_TYPE_REFERENCE) => {
+                let fields = match self.read_reference_bytes(br) {
+                    Ok(fields) => fields,
+                    // Nothing consumed, see if anything is left at all
+                    Err(ref error) if error.kind() == ErrorKind::UnexpectedEof => {
+                        return match br.read_byte() {
+                            Err(ref error) if error.kind() == ErrorKind::UnexpectedEof => Ok(None),
+                            _ => Err(Error::new(
+                                ErrorKind::UnexpectedEof,
+                                "Stream ended in the middle of a reference record",
+                            )),
+                        };
+                    }
+                    Err(error) => return Err(error),
+                };
+                let length_mask = (1 << self.match_length_nbits) - 1;
+                let position = (fields >> self.match_length_nbits) as usize;
+                let length = (fields & length_mask) as usize + self.threshold;
+                self.check_reference(position, length).map(Some)
+            }
+        }
+    }
+
+    // Reads position and length fields of a reference in the flag group layout.
+    // Nothing is consumed if stream ends before the whole reference.
+    fn read_reference_bytes<R: Read>(&mut self, br: &mut BitReader<R>) -> Result<u64> {
+        let nbits = self.reference_nbytes * 8;
+        if nbits <= 32 {
+            return br.read_bits(nbits).map(u64::from);
+        }
+        let high = u64::from(br.read_bits(32)?);
+        let low = match br.read_bits(nbits - 32) {
+            Ok(low) => u64::from(low),
+            // First part was already consumed, so this can't be mistaken for the end of a stream
+            Err(_) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Stream ended in the middle of a reference record",
+                ))
+            }
+        };
+        Ok((high << (nbits - 32)) | low)
+    }
+
+    fn write_grouped_record<W: Write>(
+        &mut self,
+        bw: &mut BitWriter<W>,
+        token: Token,
+    ) -> Result<()> {
+        match token {
+            Token::Literal(byte) => self.flags_out.push(bw, RECORD_TYPE_LITERAL, &[byte]),
+            Token::Match { position, length } => {
+                let fields = ((position as u64) << self.match_length_nbits)
+                    | (length - self.threshold) as u64;
+                let bytes = fields.to_be_bytes();
+                let record = &bytes[bytes.len() - self.reference_nbytes..];
+                self.flags_out.push(bw, RECORD_TYPE_REFERENCE, record)
+            }
+        }
+    }
+}
+
+impl RecordCodec for LzssCodec {
+    fn history_size(&self) -> usize {
+        self.history_size
+    }
+
+    fn min_match_length(&self) -> usize {
+        self.threshold
+    }
+
+    fn max_match_length(&self) -> usize {
+        self.current_window_size
+    }
+
+    // Beginning of a stream is unencoded, so that decoder has some history to start with
+    fn literal_prefix_len(&self) -> usize {
+        self.literal_prefix_len
+    }
+
+    fn preset_history(&self) -> &[u8] {
+        &self.dictionary
+    }
+
+    fn set_short_input(&mut self, len: Option<usize>) {
+        self.short_input_len = len;
+    }
+
+    fn write_header<W: Write>(&mut self, bw: &mut BitWriter<W>) -> Result<()> {
+        // Every stream starts from scratch
+        self.bits_written = 0;
+        self.bytes_processed = 0;
+
+        let flag_count = match self.config.layout() {
+            Layout::BitPacked => 0,
+            Layout::FlagGroups(flag_count) => flag_count,
+        };
+        let mut flags = 0;
+        if self.dictionary_id.is_some() {
+            flags |= HEADER_FLAG_DICTIONARY;
+        }
+        if self.short_input_len.is_some() {
+            flags |= HEADER_FLAG_SHORT_INPUT;
+        }
+        if flag_count == 0 && flags == 0 {
+            bw.write_bits(self.history_addr_nbits as u32, BITS_FOR_HISTORY_ADDR_NBTIS)?;
+            bw.write_bits(self.match_length_nbits as u32, BITS_FOR_MATCH_LENGTH_NBITS)?;
+            self.bits_written += BITS_FOR_HISTORY_ADDR_NBTIS + BITS_FOR_MATCH_LENGTH_NBITS;
+        } else {
+            let header = [
+                0,
+                self.history_addr_nbits as u8,
+                self.match_length_nbits as u8,
+                flag_count,
+                flags,
+            ];
+            bw.write_bytes(&header)?;
+            self.bits_written += header.len() * 8;
+            if let Some(id) = self.dictionary_id {
+                bw.write_bytes(&id.to_be_bytes())?;
+                self.bits_written += 4 * 8;
+            }
+            if let Some(len) = self.short_input_len {
+                bw.write_bytes(&(len as u32).to_be_bytes())?;
+                self.bits_written += 4 * 8;
+            }
+        }
+        debug!("Header: {:?}", self.config);
+        Ok(())
+    }
+
+    fn write_token<W: Write>(&mut self, bw: &mut BitWriter<W>, token: Token) -> Result<()> {
+        match token {
+            Token::Literal(byte) if self.bytes_processed < self.literal_prefix_len => {
+                bw.write_byte(byte)?;
+                self.bits_written += 8;
+            }
+            token if self.config.layout() != Layout::BitPacked => {
+                self.write_grouped_record(bw, token)?
+            }
+            Token::Literal(byte) => self.write_literal_record(bw, byte)?,
+            Token::Match { position, length } => {
+                self.write_reference_record(bw, position, length)?
+            }
+        }
+        self.bytes_processed += token.decoded_len();
+        Ok(())
+    }
+
+    fn write_prefix<W: Write>(&mut self, bw: &mut BitWriter<W>, bytes: &[u8]) -> Result<()> {
+        debug_assert!(self.bytes_processed + bytes.len() <= self.literal_prefix_len);
+        bw.write_bytes(bytes)?;
+        self.bits_written += bytes.len() * 8;
+        self.bytes_processed += bytes.len();
+        Ok(())
+    }
+
+    fn finish<W: Write>(&mut self, bw: &mut BitWriter<W>) -> Result<()> {
+        if self.config.layout() != Layout::BitPacked {
+            return self.flags_out.flush(bw);
+        }
+        // See read_next_record for the ways a stream can end
+        if !self.bits_written.is_multiple_of(8) {
+            bw.write_bit(RECORD_TYPE_LITERAL)?;
+            self.bits_written += 1;
+        }
+        bw.pad_to_byte()?;
+        debug!("Bits written: {}", self.bits_written);
+        Ok(())
+    }
+
+    #[inline]
+    fn read_token<R: Read>(&mut self, br: &mut BitReader<R>) -> Result<Option<Token>> {
+        let token = if self.bytes_processed < self.literal_prefix_len {
+            Some(Token::Literal(self.read_prefix_byte(br)?))
+        } else if self.short_input_len.is_some() {
+            // Whole input was in the unencoded beginning
+            None
+        } else if self.config.layout() == Layout::BitPacked {
+            self.read_next_record(br)?
+        } else {
+            self.read_grouped_record(br)?
+        };
+        if let Some(token) = token {
+            self.bytes_processed += token.decoded_len();
+        }
+        Ok(token)
+    }
+
+    fn decode_into<R: Read>(
+        &mut self,
+        br: &mut BitReader<R>,
+        buffer: &mut [u8],
+        end: &mut usize,
+    ) -> Result<bool> {
+        // Unencoded beginning is no longer than a match, so there is room for it
+        while self.bytes_processed < self.literal_prefix_len {
+            buffer[*end] = self.read_prefix_byte(br)?;
+            *end += 1;
+            self.bytes_processed += 1;
+        }
+        if self.short_input_len.is_some() {
+            Ok(false)
+        } else if self.config.layout() == Layout::BitPacked {
+            self.decode_records(br, buffer, end)
+        } else {
+            self.decode_groups(br, buffer, end)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOrder {
+    MsbFirst,
+    LsbFirst,
+}
+
+// Writer side of a layout where flags of several records are grouped into a control word,
+// which is followed by the records themselves.
+// Records are collected until all flags of a control word are known.
+pub struct FlagGroupWriter {
+    flag_count: usize, // Number of flags in a control word (8, 16 or 32)
+    order: BitOrder,
+    flags: u32,
+    records_in_group: usize,
+    records: Vec<u8>,
+}
+
+impl FlagGroupWriter {
+    pub fn new(flag_count: usize, order: BitOrder) -> FlagGroupWriter {
+        assert!(
+            flag_count == 8 || flag_count == 16 || flag_count == 32,
+            "Control word has to be 8, 16 or 32 bits long"
+        );
+        FlagGroupWriter {
+            flag_count,
+            order,
+            flags: 0,
+            records_in_group: 0,
+            records: Vec::new(),
+        }
+    }
+
+    // Adds a record with it's flag. Writes the group out once it is full.
+    pub fn push<W: Write>(
+        &mut self,
+        bw: &mut BitWriter<W>,
+        flag: bool,
+        record: &[u8],
+    ) -> Result<()> {
+        if flag {
+            let bit = match self.order {
+                BitOrder::MsbFirst => self.flag_count - 1 - self.records_in_group,
+                BitOrder::LsbFirst => self.records_in_group,
+            };
+            self.flags |= 1 << bit;
+        }
+        self.records.extend_from_slice(record);
+        self.records_in_group += 1;
+        if self.records_in_group == self.flag_count {
+            self.flush(bw)?;
+        }
+        Ok(())
+    }
+
+    // Writes out an incomplete group. Unused flags are zero.
+    pub fn flush<W: Write>(&mut self, bw: &mut BitWriter<W>) -> Result<()> {
+        if self.records_in_group > 0 {
+            bw.write_bits(self.flags, self.flag_count)?;
+            bw.write_bytes(&self.records)?;
+            self.flags = 0;
+            self.records_in_group = 0;
+            self.records.clear();
+        }
+        Ok(())
+    }
+}
+
+// Reader side of grouped flags (see FlagGroupWriter)
+pub struct FlagGroupReader {
+    flag_count: usize,
+    order: BitOrder,
+    flags: u32,
+    remaining: usize,
+}
+
+impl FlagGroupReader {
+    pub fn new(flag_count: usize, order: BitOrder) -> FlagGroupReader {
+        FlagGroupReader {
+            flag_count,
+            order,
+            flags: 0,
+            remaining: 0,
+        }
+    }
+
+    // Returns flag of the next record, reading a new control word when needed.
+    // Returns None if stream ends where a control word should start.
+    pub fn next_flag<R: Read>(&mut self, br: &mut BitReader<R>) -> Result<Option<bool>> {
+        if self.remaining == 0 {
+            match br.read_bits(self.flag_count) {
+                Ok(flags) => self.start_group(flags),
+                Err(ref error) if error.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(Some(self.take_flag()))
+    }
+
+    // Whether flags of the current control word are used up
+    #[inline]
+    pub fn group_ended(&self) -> bool {
+        self.remaining == 0
+    }
+
+    // Starts a group with a control word read by the caller
+    #[inline]
+    pub fn start_group(&mut self, flags: u32) {
+        self.flags = flags;
+        self.remaining = self.flag_count;
+    }
+
+    // Flag of the next record of the current group
+    #[inline]
+    pub fn take_flag(&mut self) -> bool {
+        debug_assert!(self.remaining > 0, "Group has ended");
+        let index = self.flag_count - self.remaining;
+        let bit = match self.order {
+            BitOrder::MsbFirst => self.flag_count - 1 - index,
+            BitOrder::LsbFirst => index,
+        };
+        self.remaining -= 1;
+        self.flags & (1 << bit) != 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decoder::{self, Decoder, TokenReader};
+    use crate::encoder::Encoder;
+
+    #[test]
+    fn flag_group_layouts_round_trip() {
+        // Long enough for groups to be split where decoded output is written out
+        let data = std::fs::read("test-files/fields.c").unwrap().repeat(8);
+        for &(history_addr_nbits, flag_count) in &[(12, 8), (12, 16), (31, 32)] {
+            let config =
+                Config::new(history_addr_nbits, 4, 0).with_layout(Layout::FlagGroups(flag_count));
+            let mut archive = Vec::new();
+            Encoder::with_config(config)
+                .encode(&mut data.as_slice(), &mut archive)
+                .unwrap();
+            assert_eq!(
+                TokenReader::new(archive.as_slice()).unwrap().config(),
+                &config
+            );
+
+            let mut decoded = Vec::new();
+            decoder::decode(archive.as_slice(), &mut decoded).unwrap();
+            assert_eq!(decoded, data);
+        }
+    }
+
+    #[test]
+    fn preset_dictionary() {
+        let dictionary = br#"{"id": 0, "name": "", "tags": [], "active": false}"#;
+        let message = br#"{"id": 17, "name": "lzss", "tags": ["a"], "active": true}"#;
+        for &layout in &[Layout::BitPacked, Layout::FlagGroups(8)] {
+            let config = Config::new(10, 4, 0).with_layout(layout);
+            let mut plain = Vec::new();
+            Encoder::with_config(config)
+                .encode(&mut &message[..], &mut plain)
+                .unwrap();
+            let mut archive = Vec::new();
+            Encoder::with_config(config)
+                .with_dictionary(dictionary)
+                .encode(&mut &message[..], &mut archive)
+                .unwrap();
+            assert!(archive.len() < plain.len());
+
+            let mut decoded = Vec::new();
+            Decoder::with_dictionary(archive.as_slice(), &mut decoded, dictionary)
+                .unwrap()
+                .decode()
+                .unwrap();
+            assert_eq!(&decoded[..], &message[..]);
+
+            assert!(decoder::decode(archive.as_slice(), &mut Vec::new()).is_err());
+            assert!(Decoder::with_dictionary(archive.as_slice(), Vec::new(), b"other").is_err());
+        }
+    }
+
+    #[test]
+    fn inputs_shorter_than_prefix() {
+        let data = b"0123456789abcdefg";
+        for len in 0..data.len() {
+            for &layout in &[Layout::BitPacked, Layout::FlagGroups(8)] {
+                let mut archive = Vec::new();
+                Encoder::with_config(Config::new(12, 4, 0).with_layout(layout))
+                    .encode(&mut &data[..len], &mut archive)
+                    .unwrap();
+                let mut decoded = Vec::new();
+                decoder::decode(archive.as_slice(), &mut decoded).unwrap();
+                assert_eq!(&decoded[..], &data[..len]);
+
+                let tokens = data[..len].iter().map(|&byte| Token::Literal(byte));
+                let mut from_tokens = Vec::new();
+                Encoder::with_config(Config::new(12, 4, 0).with_layout(layout))
+                    .encode_tokens(tokens, &mut from_tokens)
+                    .unwrap();
+                assert_eq!(from_tokens, archive);
+
+                if len > 0 {
+                    let truncated = &archive[..archive.len() - 1];
+                    assert!(decoder::decode(truncated, &mut Vec::new()).is_err());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_archives_fail() {
+        let data = std::fs::read("test-files/fields.c").unwrap();
+        let config = Config::new(12, 4, 0);
+        // Header and unencoded beginning end within these many bytes
+        let prefix_end = |layout| match layout {
+            Layout::BitPacked => (9 + config.current_window_size() * 8).div_ceil(8),
+            Layout::FlagGroups(_) => 5 + config.current_window_size(),
+        };
+        for &layout in &[Layout::BitPacked, Layout::FlagGroups(8)] {
+            let mut archive = Vec::new();
+            Encoder::with_config(config.with_layout(layout))
+                .encode(&mut data.as_slice(), &mut archive)
+                .unwrap();
+            for len in 0..prefix_end(layout) {
+                let error = decoder::decode(&archive[..len], &mut Vec::new()).unwrap_err();
+                assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+            }
+        }
+    }
+}
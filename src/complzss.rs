@@ -0,0 +1,143 @@
+// Apple "complzss" container used for kernelcaches and older firmware images.
+// A 0x180 byte header (all fields big endian) is followed by a stream in the format of LZSS.C
+// (see `okumura`):
+//   0x00 magic "comp"
+//   0x04 compression type "lzss"
+//   0x08 adler32 of uncompressed data
+//   0x0c uncompressed size
+//   0x10 compressed size
+//   0x14 reserved, platform name (64 bytes) and root path (256 bytes), zeros when encoding
+use super::checksum::{adler32, checksum_mismatch, ChecksumWriter};
+use super::okumura;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+pub const HEADER_SIZE: usize = 0x180;
+pub const MAGIC: &[u8; 4] = b"comp";
+pub const COMPRESSION_TYPE: &[u8; 4] = b"lzss";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub adler32: u32,
+    pub uncompressed_size: u32,
+    pub compressed_size: u32,
+}
+
+impl Header {
+    pub fn read<R: Read>(reader: &mut R) -> Result<Header> {
+        let mut header = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        if &header[0..4] != MAGIC || &header[4..8] != COMPRESSION_TYPE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Invalid header: not a complzss container",
+            ));
+        }
+        let field = |offset: usize| {
+            u32::from_be_bytes([
+                header[offset],
+                header[offset + 1],
+                header[offset + 2],
+                header[offset + 3],
+            ])
+        };
+        Ok(Header {
+            adler32: field(0x08),
+            uncompressed_size: field(0x0c),
+            compressed_size: field(0x10),
+        })
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut header = [0u8; HEADER_SIZE];
+        header[0..4].copy_from_slice(MAGIC);
+        header[4..8].copy_from_slice(COMPRESSION_TYPE);
+        header[0x08..0x0c].copy_from_slice(&self.adler32.to_be_bytes());
+        header[0x0c..0x10].copy_from_slice(&self.uncompressed_size.to_be_bytes());
+        header[0x10..0x14].copy_from_slice(&self.compressed_size.to_be_bytes());
+        writer.write_all(&header)
+    }
+}
+
+// Whole input is read into memory first, because the header needs it's size and checksum
+pub fn encode<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<()> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    let mut compressed = Vec::new();
+    okumura::encode(&mut data.as_slice(), &mut compressed)?;
+    if data.len() > u32::MAX as usize || compressed.len() > u32::MAX as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Input is too large for a complzss container",
+        ));
+    }
+    let header = Header {
+        adler32: adler32(&data),
+        uncompressed_size: data.len() as u32,
+        compressed_size: compressed.len() as u32,
+    };
+    header.write(writer)?;
+    writer.write_all(&compressed)?;
+    writer.flush()
+}
+
+// Decodes a container and verifies size and checksum of the result.
+// Output is written as it is decoded, so it has to be discarded if an error is returned.
+pub fn decode<R: Read, W: Write>(mut reader: R, writer: W) -> Result<()> {
+    let header = Header::read(&mut reader)?;
+    let mut output = ChecksumWriter::new(writer);
+    okumura::decode(reader.take(u64::from(header.compressed_size)), &mut output)?;
+    if output.bytes_written() != u64::from(header.uncompressed_size) {
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            "Decoded size does not match the header",
+        ))
+    } else if output.sum() != header.adler32 {
+        Err(checksum_mismatch(
+            "Checksum of decoded data does not match the header",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn container(adler32: u32) -> Vec<u8> {
+        // Stream of LZSS.C for "abcabcabcabc  xyz" (see `okumura` tests)
+        let stream = [
+            0xf7, b'a', b'b', b'c', 0xee, 0xf6, b' ', b' ', b'x', b'y', 0x01, b'z',
+        ];
+        let mut container = vec![0u8; HEADER_SIZE];
+        container[0..8].copy_from_slice(b"complzss");
+        container[0x08..0x0c].copy_from_slice(&adler32.to_be_bytes());
+        container[0x0c..0x10].copy_from_slice(&[0, 0, 0, 17]);
+        container[0x10..0x14].copy_from_slice(&[0, 0, 0, stream.len() as u8]);
+        container.extend_from_slice(&stream);
+        // Data after the compressed stream is not part of it
+        container.extend_from_slice(&[0xff; 8]);
+        container
+    }
+
+    #[test]
+    fn decodes_and_verifies_checksum() {
+        let mut decoded = Vec::new();
+        decode(container(0x38d1_0644).as_slice(), &mut decoded).unwrap();
+        assert_eq!(decoded, b"abcabcabcabc  xyz");
+
+        let err = decode(container(0x38d1_0645).as_slice(), &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = std::fs::read("test-files/fields.c").unwrap();
+        let mut archive = Vec::new();
+        encode(&mut data.as_slice(), &mut archive).unwrap();
+        assert_eq!(&archive[0..8], b"complzss");
+        let mut decoded = Vec::new();
+        decode(archive.as_slice(), &mut decoded).unwrap();
+        assert_eq!(decoded, data);
+    }
+}
@@ -1,212 +1,105 @@
+use super::bit_io::BitReader;
+use super::codec::{LzssCodec, RecordCodec};
+use super::output_window::{OutputWindow, COPY_SLACK};
 use super::*;
-use bitbit::{BitReader, MSB};
-use log::debug;
-use slice_deque::SliceDeque;
 use std::io::{Error, ErrorKind, Read, Result, Write};
 
-// TODO: Make encoder use this too?
-#[derive(Debug)]
-enum Record {
-    Reference { position: usize, length: usize },
-    Literal { byte: u8 },
+// Reads tokens from an encoded stream
+pub struct TokenReader<R: Read, C: RecordCodec = LzssCodec> {
+    br: BitReader<R>,
+    codec: C,
 }
 
-pub struct Decoder<R: Read, W: Write> {
-    br: BitReader<R, MSB>,
-    writer: W,
-    history_addr_nbits: usize,
-    match_length_nbits: usize,
-    history: SliceDeque<u8>,
-    history_size: usize,
-    current_window_size: usize,
-    threshold: usize,
-}
-
-impl<R: Read, W: Write> Decoder<R, W> {
-    pub fn new(reader: R, writer: W) -> Result<Decoder<R, W>> {
-        let mut br: BitReader<_, MSB> = BitReader::new(reader);
-
-        let (history_addr_nbits, match_length_nbits) = Decoder::<R, W>::read_header(&mut br)?;
-        debug!("Header: ({}, {})", history_addr_nbits, match_length_nbits);
-        assert!(
-            history_addr_nbits >= MIN_HISTORY_ADDR_BITS
-                && history_addr_nbits <= MAX_HISTORY_ADDR_BITS
-        );
-        assert!(
-            match_length_nbits >= MIN_MATCH_LENGTH_BITS
-                && match_length_nbits <= MAX_MATCH_LENGTH_BITS
-        );
-
-        let history_size: usize = usize::pow(2, history_addr_nbits as u32);
-        let threshold = calc_threshold(history_addr_nbits, match_length_nbits);
-        let current_window_size = usize::pow(2, match_length_nbits as u32) + threshold - 1;
-
-        Ok(Decoder {
-            br,
-            writer,
-            history_addr_nbits,
-            match_length_nbits,
-            history: SliceDeque::<u8>::with_capacity(history_size),
-            history_size,
-            current_window_size,
-            threshold,
-        })
-    }
-
-    pub fn decode(&mut self) -> Result<()> {
-        // Read beginning of a file
-        self.init()?;
-
-        // debug!("Initial history: {}", std::str::from_utf8_unchecked(self.history.as_slice()));
-        debug!("Initial history: {:?}", self.history.as_slice());
-        debug!("Initial history length: {}", self.history.len());
-
-        // Decode rest of a file
-        loop {
-            if let Some(record) = self.read_next_record()? {
-                debug!("Record: {:?}", &record);
-                self.write_decoded(&record)?;
-            } else {
-                // None returned from read_next_record means file has ended
-                self.writer.flush()?;
-                return Ok(());
-            }
+impl<R: Read> TokenReader<R> {
+    // Reads header of a stream in the format of this crate
+    pub fn new(reader: R) -> Result<TokenReader<R>> {
+        let mut br = BitReader::new(reader);
+        let codec = LzssCodec::read_header(&mut br)?;
+        if codec.dictionary_id().is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Stream was encoded with a preset dictionary",
+            ));
         }
+        Ok(TokenReader { br, codec })
     }
 
-    // Returns None if file ends
-    // Else the next Record if or an error
-    #[allow(clippy::match_bool)]
-    fn read_next_record(&mut self) -> Result<Option<Record>> {
-        // There are two valid ways for an archive file to end:
-        //  1. At the byte boundary (if the end of the last record is at the byte boundary)
-        //  2. Or if last record does not end at byte boundary,
-        //  it has to end with a 1 and padding till the next byte boundary
-        //  (which creates an invalid literal record - that's how we know it's the end).
-        // If we get EOF when reading type bit, it's the first type of ending.
-        // If we get a literal type bit and EOF while reading it's byte, it means it's the second type of ending.
-        // Every other case of EOF is interpreted as InvalidData error.
+    // Reads header of a stream encoded with a preset dictionary
+    pub fn with_dictionary(reader: R, dictionary: &[u8]) -> Result<TokenReader<R>> {
+        let mut br = BitReader::new(reader);
+        let mut codec = LzssCodec::read_header(&mut br)?;
+        codec.load_dictionary(dictionary)?;
+        Ok(TokenReader { br, codec })
+    }
 
-        match self.br.read_bit() {
-            Ok(rec_type_bit) => match rec_type_bit {
-                RECORD_TYPE_LITERAL => self.read_literal(),
-                RECORD_TYPE_REFERENCE => self.read_reference(),
-            },
-            Err(error) => match error.kind() {
-                ErrorKind::UnexpectedEof => Ok(None),
-                _ => Err(Error::new(
-                    error.kind(),
-                    format!("Error reading a record type bit: {}", error),
-                )),
-            },
-        }
+    // Configuration stream was encoded with (search depth is not stored and is always 0)
+    pub fn config(&self) -> &Config {
+        self.codec.config()
     }
+}
 
-    fn read_literal(&mut self) -> Result<Option<Record>> {
-        match self.br.read_byte() {
-            Ok(byte) => Ok(Some(Record::Literal { byte })),
-            Err(error) => match error.kind() {
-                ErrorKind::UnexpectedEof => Ok(None),
-                _ => Err(Error::new(
-                    error.kind(),
-                    format!("Error while reading a literal record: {}", error),
-                )),
-            },
+impl<R: Read, C: RecordCodec> TokenReader<R, C> {
+    // Reads a stream in a format of `codec`. Header (if the format has one) should already be read.
+    pub fn with_codec(reader: R, codec: C) -> TokenReader<R, C> {
+        TokenReader {
+            br: BitReader::new(reader),
+            codec,
         }
     }
 
-    fn read_reference(&mut self) -> Result<Option<Record>> {
-        let handle_error = |res: Result<(u32)>| match res {
-            Err(error) => Err(Error::new(
-                error.kind(),
-                format!("Error while reading a reference record: {}", error),
-            )),
-            Ok(r) => Ok(r),
-        };
-        let position = handle_error(self.br.read_bits(self.history_addr_nbits))? as usize;
-        let length =
-            handle_error(self.br.read_bits(self.match_length_nbits))? as usize + self.threshold;
-
-        if position >= self.history.len() {
-            return Err(Error::new(
-                ErrorKind::InvalidData,
-                "Invalid record: position bigger than curret history size",
-            ));
-        } else if length > self.current_window_size {
-            return Err(Error::new(
-                ErrorKind::InvalidData,
-                "Invalid record: length bigger than possible current window size",
-            ));
-        }
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
 
-        Ok(Some(Record::Reference { position, length }))
+    // Number of bits of the stream read so far, header included
+    pub fn bit_position(&self) -> u64 {
+        self.br.bit_position()
     }
 
-    // Read unencoded beginning of a file
-    // Write it, initialize history with it
-    fn init(&mut self) -> Result<()> {
-        // TODO: Optimize to read in words of 32bits
-        for _ in 0..self.current_window_size {
-            match self.br.read_byte() {
-                Ok(byte) => self.write_decoded(&Record::Literal { byte })?,
-                Err(err) => {
-                    return Err(Error::new(
-                        err.kind(),
-                        format!("Error while reading beginning of a file: {}", err),
-                    ))
-                }
-            }
-        }
-        Ok(())
+    // Returns None if stream ends
+    #[inline]
+    pub fn read_token(&mut self) -> Result<Option<Token>> {
+        self.codec.read_token(&mut self.br)
     }
+}
 
-    // Writes to file and updates history
-    fn write_decoded(&mut self, record: &Record) -> Result<()> {
-        let byte_vec: Vec<u8> = match record {
-            Record::Literal { byte } => vec![*byte],
-            Record::Reference { position, length } => {
-                // TODO: Delete
-                if *position == 2413 && *length == 16 {
-                    assert!(*length <= self.current_window_size);
-                }
-                Vec::from(&self.history[*position..*position + *length])
-            }
-        };
-        let bytes = byte_vec.as_slice();
+pub struct Decoder<R: Read, W: Write, C: RecordCodec = LzssCodec> {
+    tokens: TokenReader<R, C>,
+    output: OutputWindow<W>,
+}
 
-        //debug!("Writing: {}", std::str::from_utf8(bytes).unwrap());
-        // Could hang?
-        self.writer.write_all(bytes)?;
+impl<R: Read, W: Write> Decoder<R, W> {
+    pub fn new(reader: R, writer: W) -> Result<Decoder<R, W>> {
+        let tokens = TokenReader::new(reader)?;
+        Ok(Decoder::from_tokens(tokens, writer))
+    }
 
-        // Update history
-        let new_size = self.history.len() + bytes.len();
-        if new_size > self.history_size {
-            let to_pop = new_size - self.history_size;
-            for _ in 0..to_pop {
-                self.history.pop_front();
-            }
-        }
+    // Decodes a stream encoded with a preset dictionary (see `Encoder::with_dictionary`)
+    pub fn with_dictionary(reader: R, writer: W, dictionary: &[u8]) -> Result<Decoder<R, W>> {
+        let tokens = TokenReader::with_dictionary(reader, dictionary)?;
+        Ok(Decoder::from_tokens(tokens, writer))
+    }
+}
 
-        for byte in bytes {
-            self.history.push_back(*byte);
-        }
+impl<R: Read, W: Write, C: RecordCodec> Decoder<R, W, C> {
+    pub fn with_codec(reader: R, writer: W, codec: C) -> Decoder<R, W, C> {
+        Decoder::from_tokens(TokenReader::with_codec(reader, codec), writer)
+    }
 
-        Ok(())
+    fn from_tokens(tokens: TokenReader<R, C>, writer: W) -> Decoder<R, W, C> {
+        let codec = tokens.codec();
+        let output =
+            OutputWindow::with_history(writer, codec.history_size(), codec.preset_history());
+        Decoder { tokens, output }
     }
 
-    fn read_header(br: &mut BitReader<R, MSB>) -> Result<(usize, usize)> {
-        let res: Result<(usize, usize)> = {
-            let history_nbits = br.read_bits(BITS_FOR_HISTORY_ADDR_NBTIS)?;
-            let match_len_nbits = br.read_bits(BITS_FOR_MATCH_LENGTH_NBITS)?;
-            Ok((history_nbits as usize, match_len_nbits as usize))
-        };
-        match res {
-            Ok(r) => Ok(r),
-            Err(err) => Err(Error::new(
-                err.kind(),
-                format!("Error while reading header: {}", err),
-            )),
-        }
+    // Records are decoded by the codec straight into the output window
+    pub fn decode(&mut self) -> Result<()> {
+        let TokenReader { br, codec } = &mut self.tokens;
+        let room = codec.max_match_length() + COPY_SLACK;
+        self.output
+            .decode_with(room, |buffer, end| codec.decode_into(br, buffer, end))?;
+        self.output.flush()
     }
 }
 
@@ -214,9 +107,3 @@ pub fn decode<R: Read, W: Write>(reader: R, writer: W) -> Result<()> {
     let mut decoder = Decoder::new(reader, writer)?;
     decoder.decode()
 }
-
-// fn write_header<W: Write>(&self, bw: &mut BitWriter<W>) -> Result<()> {
-//     bw.write_bits(self.history_addr_nbits as u32, BITS_FOR_HISTORY_ADDR_NBTIS)?;
-//     bw.write_bits(self.match_length_nbits as u32, BITS_FOR_MATCH_LENGTH_NBITS)?;
-//     Ok(())
-// }
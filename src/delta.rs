@@ -0,0 +1,73 @@
+// Delta compression: new version of a file is encoded with the old one as a preset dictionary,
+// so unchanged parts become references into it. Patch is a regular stream of this crate, with
+// the ID of the old file in the header (see `Encoder::with_dictionary`).
+use super::decoder::Decoder;
+use super::encoder::Encoder;
+use super::match_finder::HashChain;
+use super::*;
+use std::io::{Read, Result, Write};
+
+// Largest history used for a reference file by default (64 MiB). Only the end of bigger
+// files can be referenced.
+pub const MAX_DEFAULT_HISTORY_ADDR_BITS: u8 = 26;
+// Long matches, unchanged parts of a file are big
+const MATCH_LENGTH_BITS: u8 = 12;
+// How many candidate matches are checked for every position
+const MAX_CHAIN: usize = 256;
+
+// Configuration with a history big enough to hold the whole reference file (up to
+// `MAX_DEFAULT_HISTORY_ADDR_BITS`)
+pub fn config_for(old_len: usize) -> Config {
+    let mut history_addr_nbits = MATCH_LENGTH_BITS + 1;
+    while history_addr_nbits < MAX_DEFAULT_HISTORY_ADDR_BITS
+        && (1usize << history_addr_nbits) < old_len
+    {
+        history_addr_nbits += 1;
+    }
+    Config::new(history_addr_nbits, MATCH_LENGTH_BITS, 0)
+}
+
+// Writes a patch which turns `old` into data read from `new`
+pub fn diff<R: Read, W: Write>(old: &[u8], new: &mut R, patch: &mut W) -> Result<()> {
+    diff_with_config(old, new, patch, config_for(old.len()))
+}
+
+pub fn diff_with_config<R: Read, W: Write>(
+    old: &[u8],
+    new: &mut R,
+    patch: &mut W,
+    config: Config,
+) -> Result<()> {
+    let finder = HashChain::new(config.history_size(), MAX_CHAIN);
+    Encoder::with_match_finder(config, Box::new(finder))
+        .with_dictionary(old)
+        .encode(new, patch)
+}
+
+// Applies a patch to `old`. Fails if patch was made against a different file.
+pub fn patch<R: Read, W: Write>(old: &[u8], patch: R, new: W) -> Result<()> {
+    Decoder::with_dictionary(patch, new, old)?.decode()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn patch_reproduces_new_version() {
+        let old = std::fs::read("test-files/kennedy.xls").unwrap();
+        let mut new = old.clone();
+        new[1000..1100].copy_from_slice(&[0x5a; 100]);
+        new.splice(300_000..300_000, b"inserted bytes".iter().copied());
+        new.truncate(new.len() - 5000);
+
+        let mut delta = Vec::new();
+        diff(&old, &mut new.as_slice(), &mut delta).unwrap();
+        assert!(delta.len() < new.len() / 100);
+
+        let mut patched = Vec::new();
+        patch(&old, delta.as_slice(), &mut patched).unwrap();
+        assert_eq!(patched, new);
+        assert!(patch(&old[1..], delta.as_slice(), Vec::new()).is_err());
+    }
+}
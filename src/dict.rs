@@ -0,0 +1,146 @@
+// Training of preset dictionaries (see `Encoder::with_dictionary`).
+// Short substrings (k-mers) are scored by the number of samples they appear in, since those are
+// the ones later messages will find matches for. Dictionary is built greedily from segments of
+// samples with the highest total score, counting every k-mer only once in the whole dictionary.
+// Best segments are placed at the end, so they survive if a dictionary is cut to history size.
+use std::collections::{BinaryHeap, HashMap};
+
+// Length of substrings which are counted. About the shortest match worth encoding.
+const KMER_SIZE: usize = 6;
+// Length of pieces dictionary is assembled from
+const SEGMENT_SIZE: usize = 32;
+
+struct KmerStats<'a> {
+    // Score of a k-mer and index of the last sample it was counted for
+    kmers: HashMap<&'a [u8], (u32, usize)>,
+}
+
+impl<'a> KmerStats<'a> {
+    fn new(samples: &[&'a [u8]]) -> KmerStats<'a> {
+        let mut kmers = HashMap::new();
+        for (index, sample) in samples.iter().enumerate() {
+            for kmer in sample.windows(KMER_SIZE) {
+                let entry = kmers.entry(kmer).or_insert((0, usize::MAX));
+                if entry.1 != index {
+                    *entry = (entry.0 + 1, index);
+                }
+            }
+        }
+        // Substrings of a single sample don't help with other messages
+        for entry in kmers.values_mut() {
+            if entry.0 < 2 {
+                entry.0 = 0;
+            }
+        }
+        KmerStats { kmers }
+    }
+
+    fn score(&self, segment: &[u8]) -> u32 {
+        let mut seen: Vec<&[u8]> = Vec::with_capacity(SEGMENT_SIZE);
+        let mut score = 0;
+        for kmer in segment.windows(KMER_SIZE) {
+            if !seen.contains(&kmer) {
+                seen.push(kmer);
+                score += self.kmers.get(kmer).map_or(0, |entry| entry.0);
+            }
+        }
+        score
+    }
+
+    // K-mers of a segment which is in the dictionary are not worth anything anymore
+    fn cover(&mut self, segment: &[u8]) {
+        for kmer in segment.windows(KMER_SIZE) {
+            if let Some(entry) = self.kmers.get_mut(kmer) {
+                entry.0 = 0;
+            }
+        }
+    }
+}
+
+// Builds a dictionary of at most `size` bytes from substrings common to `samples`.
+// Dictionary is shorter if samples don't have enough in common.
+pub fn train(samples: &[&[u8]], size: usize) -> Vec<u8> {
+    let mut stats = KmerStats::new(samples);
+    let segment = |sample: usize, pos: usize| {
+        let data = samples[sample];
+        &data[pos..usize::min(pos + SEGMENT_SIZE, data.len())]
+    };
+
+    // Scores only go down as segments are chosen, so a segment whose score is still the same
+    // after being taken from the heap is the best one
+    let mut heap = BinaryHeap::new();
+    for (index, sample) in samples.iter().enumerate() {
+        for pos in 0..sample.len().saturating_sub(KMER_SIZE - 1) {
+            let score = stats.score(segment(index, pos));
+            if score > 0 {
+                heap.push((score, index, pos));
+            }
+        }
+    }
+
+    let mut chosen: Vec<&[u8]> = Vec::new();
+    let mut chosen_len = 0;
+    while let Some((score, index, pos)) = heap.pop() {
+        if chosen_len >= size {
+            break;
+        }
+        let current = stats.score(segment(index, pos));
+        if current == score {
+            stats.cover(segment(index, pos));
+            chosen.push(segment(index, pos));
+            chosen_len += segment(index, pos).len();
+        } else if current > 0 {
+            heap.push((current, index, pos));
+        }
+    }
+
+    let mut dictionary: Vec<u8> = chosen
+        .iter()
+        .rev()
+        .flat_map(|s| s.iter().copied())
+        .collect();
+    if dictionary.len() > size {
+        dictionary.drain(0..dictionary.len() - size);
+    }
+    dictionary
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encoder::Encoder;
+
+    #[test]
+    fn dictionary_improves_small_messages() {
+        let messages: Vec<Vec<u8>> = (0..50)
+            .map(|i| {
+                format!(
+                    r#"{{"user_id": {}, "event": "page_view", "path": "/items/{}", "referrer": null}}"#,
+                    i * 7919 % 1000,
+                    i
+                )
+                .into_bytes()
+            })
+            .collect();
+        let samples: Vec<&[u8]> = messages[..40].iter().map(|m| m.as_slice()).collect();
+        let dictionary = train(&samples, 1024);
+        assert!(!dictionary.is_empty() && dictionary.len() <= 1024);
+
+        let encoded_size = |dictionary: Option<&[u8]>| {
+            let mut total = 0;
+            for message in &messages[40..] {
+                let mut encoder = Encoder::new(10, 4, 0);
+                if let Some(dictionary) = dictionary {
+                    encoder = encoder.with_dictionary(dictionary);
+                }
+                let mut archive = Vec::new();
+                encoder
+                    .encode(&mut message.as_slice(), &mut archive)
+                    .unwrap();
+                total += archive.len();
+            }
+            total
+        };
+        assert!(encoded_size(Some(&dictionary)) * 2 < encoded_size(None));
+    }
+}
@@ -0,0 +1,201 @@
+// Record by record listing of a stream in the format of this crate, for debugging the format.
+use super::codec::RecordCodec;
+use super::decoder::TokenReader;
+use super::*;
+use std::io::{Read, Result, Write};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    // Where the record starts in the stream. Control word of a flag group is read with it's
+    // first record.
+    pub bit_offset: u64,
+    // Where the expanded bytes start in decoded data
+    pub output_offset: u64,
+    pub token: Token,
+    pub bytes: Vec<u8>,
+}
+
+impl Record {
+    pub fn to_text(&self) -> String {
+        let bytes: String = self
+            .bytes
+            .iter()
+            .flat_map(|&byte| std::ascii::escape_default(byte))
+            .map(char::from)
+            .collect();
+        match self.token {
+            Token::Literal(_) => format!(
+                "{:>10} {:>10} literal              \"{}\"",
+                self.bit_offset, self.output_offset, bytes
+            ),
+            Token::Match { position, length } => format!(
+                "{:>10} {:>10} match   {:>7} {:>4} \"{}\"",
+                self.bit_offset, self.output_offset, position, length, bytes
+            ),
+        }
+    }
+
+    // Single line JSON object, bytes are hex encoded
+    pub fn to_json(&self) -> String {
+        let bytes: String = self
+            .bytes
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let fields = match self.token {
+            Token::Literal(_) => String::from("\"type\":\"literal\""),
+            Token::Match { position, length } => format!(
+                "\"type\":\"match\",\"position\":{},\"length\":{}",
+                position, length
+            ),
+        };
+        format!(
+            "{{\"bit_offset\":{},\"output_offset\":{},{},\"bytes\":\"{}\"}}",
+            self.bit_offset, self.output_offset, fields, bytes
+        )
+    }
+}
+
+// Reads records of a stream along with the bytes they expand to
+pub struct RecordReader<R: Read> {
+    tokens: TokenReader<R>,
+    // Preset dictionary followed by decoded data. Only the last history_size bytes are needed,
+    // older ones are dropped once in a while.
+    history: Vec<u8>,
+    output_offset: u64,
+}
+
+impl<R: Read> RecordReader<R> {
+    // Reads header of the stream. Streams encoded with a preset dictionary need the
+    // `dictionary`.
+    pub fn new(reader: R, dictionary: Option<&[u8]>) -> Result<RecordReader<R>> {
+        let tokens = match dictionary {
+            Some(dictionary) => TokenReader::with_dictionary(reader, dictionary)?,
+            None => TokenReader::new(reader)?,
+        };
+        let history = tokens.codec().preset_history().to_vec();
+        Ok(RecordReader {
+            tokens,
+            history,
+            output_offset: 0,
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        self.tokens.config()
+    }
+
+    // Returns None if stream ends
+    pub fn read_record(&mut self) -> Result<Option<Record>> {
+        let bit_offset = self.tokens.bit_position();
+        let token = match self.tokens.read_token()? {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        let start = self.history.len();
+        match token {
+            Token::Literal(byte) => self.history.push(byte),
+            Token::Match { position, length } => {
+                let history_size = self.tokens.codec().history_size();
+                let history_len = usize::min(self.history.len(), history_size);
+                let from = self.history.len() - history_len + position;
+                // Source may overlap the bytes being produced
+                for index in from..from + length {
+                    let byte = self.history[index];
+                    self.history.push(byte);
+                }
+            }
+        }
+        let record = Record {
+            bit_offset,
+            output_offset: self.output_offset,
+            token,
+            bytes: self.history[start..].to_vec(),
+        };
+        self.output_offset += token.decoded_len() as u64;
+
+        let history_size = self.tokens.codec().history_size();
+        if self.history.len() > 2 * history_size {
+            self.history.drain(..self.history.len() - history_size);
+        }
+        Ok(Some(record))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    // Line per record, preceded by a header line starting with #
+    Text,
+    // Object with the header fields and an array of records, one per line
+    Json,
+}
+
+pub fn dump<R: Read, W: Write>(
+    reader: R,
+    dictionary: Option<&[u8]>,
+    writer: &mut W,
+    format: DumpFormat,
+) -> Result<()> {
+    let mut records = RecordReader::new(reader, dictionary)?;
+    let config = *records.config();
+    match format {
+        DumpFormat::Text => {
+            writeln!(
+                writer,
+                "# history {} bits, match length {} bits, threshold {}, {:?}",
+                config.history_addr_nbits(),
+                config.match_length_nbits(),
+                config.threshold(),
+                config.layout()
+            )?;
+            writeln!(writer, "#  bit off    out off type    position  len bytes")?;
+            while let Some(record) = records.read_record()? {
+                writeln!(writer, "{}", record.to_text())?;
+            }
+        }
+        DumpFormat::Json => {
+            write!(
+                writer,
+                "{{\"history_addr_bits\":{},\"match_length_bits\":{},\"threshold\":{},\"records\":[",
+                config.history_addr_nbits(),
+                config.match_length_nbits(),
+                config.threshold()
+            )?;
+            let mut separator = "";
+            while let Some(record) = records.read_record()? {
+                write!(writer, "{}\n{}", separator, record.to_json())?;
+                separator = ",";
+            }
+            writeln!(writer, "\n]}}")?;
+        }
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encoder::Encoder;
+
+    #[test]
+    fn records_expand_to_decoded_data() {
+        let data = std::fs::read("test-files/alice29.txt").unwrap();
+        let data = &data[..30_000];
+        let mut archive = Vec::new();
+        Encoder::new(8, 3, 0)
+            .encode(&mut &data[..], &mut archive)
+            .unwrap();
+
+        let mut records = RecordReader::new(archive.as_slice(), None).unwrap();
+        let mut decoded = Vec::new();
+        let mut bit_offset = 0;
+        while let Some(record) = records.read_record().unwrap() {
+            assert_eq!(record.output_offset, decoded.len() as u64);
+            assert!(record.bit_offset > bit_offset);
+            bit_offset = record.bit_offset;
+            decoded.extend_from_slice(&record.bytes);
+        }
+        assert_eq!(decoded, data);
+        assert!(bit_offset < archive.len() as u64 * 8);
+    }
+}
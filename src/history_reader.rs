@@ -1,44 +1,54 @@
 //use bitbit::{BitReader, BitWriter, MSB};
-use slice_deque::SliceDeque;
-use std::cmp;
-use std::io::{Error, ErrorKind, Read, Result};
+use super::window_buffer::WindowBuffer;
 use log::debug;
+use std::cmp;
+use std::io::{ErrorKind, Read, Result};
 
 pub struct HistoryReader<R: Read> {
     reader: R,
-    buffer: SliceDeque<u8>,
+    buffer: WindowBuffer,
     history_size: usize,
     window_size: usize,
     current_history_size: usize,
+    // Reader is not asked for more after it has ended once. A terminal would wait for more input.
+    eof: bool,
 }
 
 impl<R: Read> HistoryReader<R> {
+    // History starts with `preset_history` (which is not part of input).
+    // First `initial_history_size` bytes of input are added to it without ever being in current window.
+    // Input shorter than that goes to history whole and current window is empty.
     pub fn new(
         reader: R,
         history_size: usize,
         current_window_size: usize,
+        preset_history: &[u8],
+        initial_history_size: usize,
     ) -> Result<HistoryReader<R>> {
+        assert!(initial_history_size <= current_window_size);
         let mut r = HistoryReader {
             reader,
             // current_window_size * 2 - because we have to read into this queue before popping
-            buffer: SliceDeque::with_capacity(history_size + current_window_size * 2),
+            buffer: WindowBuffer::with_capacity(history_size + current_window_size * 2),
             history_size,
             window_size: current_window_size,
             current_history_size: 0,
+            eof: false,
         };
 
-        let buff_size = current_window_size * 2;
-        r.buffer.resize(buff_size, 0);
-        let buff = &mut r.buffer[0..buff_size];
-        let bytes_read = r.reader.read(buff)?;
-        assert!(bytes_read <= buff_size);
-        if bytes_read < buff_size {
-            return Err(Error::new(
-                ErrorKind::UnexpectedEof,
-                "File has to be at least twice the size of current_window_size",
-            ));
-        } else {
-            r.current_history_size = r.window_size;
+        let preset = &preset_history[preset_history.len().saturating_sub(history_size)..];
+        let buff_size = preset.len() + initial_history_size + current_window_size;
+        r.buffer.resize(buff_size);
+        r.buffer[0..preset.len()].copy_from_slice(preset);
+        let bytes_read = r.read(preset.len(), buff_size)?;
+        let initial_history_size = cmp::min(initial_history_size, bytes_read);
+        // Input shorter than a window just makes the first window smaller
+        r.window_size = bytes_read - initial_history_size;
+        r.buffer.truncate(preset.len() + bytes_read);
+        r.current_history_size = preset.len() + initial_history_size;
+        if r.current_history_size > history_size {
+            r.buffer.drop_front(r.current_history_size - history_size);
+            r.current_history_size = history_size;
         }
 
         Ok(r)
@@ -47,19 +57,27 @@ impl<R: Read> HistoryReader<R> {
     // Returns less than end - start only when end of file is reached
     fn read(&mut self, start: usize, end: usize) -> Result<usize> {
         let mut st = start;
-        while st < end {
+        while st < end && !self.eof {
             let buffer = &mut self.buffer[st..end];
             match self.reader.read(buffer) {
-                Ok(n) => { 
-                    if n > 0 { st += n; } else { break; }
-                }
-                Err(error) => {
-                    match error.kind() {
-                        ErrorKind::Interrupted => { continue; },
-                        ErrorKind::UnexpectedEof => { break; }
-                        _ => { return Err(error); }
+                Ok(n) => {
+                    if n > 0 {
+                        st += n;
+                    } else {
+                        self.eof = true;
                     }
                 }
+                Err(error) => match error.kind() {
+                    ErrorKind::Interrupted => {
+                        continue;
+                    }
+                    ErrorKind::UnexpectedEof => {
+                        self.eof = true;
+                    }
+                    _ => {
+                        return Err(error);
+                    }
+                },
             }
         }
 
@@ -70,7 +88,6 @@ impl<R: Read> HistoryReader<R> {
     // If file has ended, current window (slice 2) starts getting smaller until it's size becomes 0.
     pub fn next(&mut self, move_bytes: usize) -> Result<(&[u8], &[u8])> {
         let buff_len = self.buffer.len();
-        assert!(self.current_history_size >= self.window_size);
         assert!(buff_len == self.current_history_size + self.window_size);
         assert!(
             move_bytes <= self.window_size,
@@ -88,7 +105,7 @@ impl<R: Read> HistoryReader<R> {
         let new_size = buff_len + move_bytes;
         // Checking if we don't exceed initial capacity
         assert!(new_size <= self.history_size + self.window_size * 2);
-        self.buffer.resize(new_size, 0);
+        self.buffer.resize(new_size);
         let bytes_read = self.read(buff_len, new_size)?;
 
         debug!(
@@ -102,9 +119,7 @@ impl<R: Read> HistoryReader<R> {
             let size_change = move_bytes - bytes_read;
             self.window_size -= size_change;
             // We resized to get exactly move_bytes. Have resize back to the actual.
-            for _ in 0..size_change {
-                self.buffer.pop_back();
-            }
+            self.buffer.truncate(buff_len + bytes_read);
             let history_diff = self.history_size - self.current_history_size;
             let history_change = cmp::min(move_bytes, history_diff);
             (move_bytes - history_change, history_change)
@@ -121,9 +136,7 @@ impl<R: Read> HistoryReader<R> {
         };
 
         self.current_history_size += history_size_change;
-        for _ in 0..to_pop {
-            self.buffer.pop_front();
-        }
+        self.buffer.drop_front(to_pop);
 
         // debug!(
         //     "current_history_size: {}, current_window_size: {}",
@@ -138,7 +151,6 @@ impl<R: Read> HistoryReader<R> {
 
     // Returns slices containing current history and current window
     pub fn current(&self) -> (&[u8], &[u8]) {
-        assert!(self.current_history_size >= self.window_size);
         assert!(self.buffer.len() == self.current_history_size + self.window_size);
 
         (
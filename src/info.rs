@@ -0,0 +1,164 @@
+// Statistics of a stream in the format of this crate, gathered by reading it's records without
+// producing any output.
+use super::codec::RecordCodec;
+use super::decoder::TokenReader;
+use super::*;
+use std::io::{Read, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamInfo {
+    pub config: Config,
+    pub dictionary_id: Option<u32>,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+    // Literals include the unencoded beginning of a stream
+    pub literals: u64,
+    pub references: u64,
+    // Bytes produced by references
+    pub match_bytes: u64,
+    // Number of references by distance. Bucket i holds distances of [2^i, 2^(i+1)).
+    pub distance_histogram: Vec<u64>,
+}
+
+impl StreamInfo {
+    // Compressed size as a fraction of uncompressed size
+    pub fn ratio(&self) -> f64 {
+        if self.uncompressed_size == 0 {
+            0.0
+        } else {
+            self.compressed_size as f64 / self.uncompressed_size as f64
+        }
+    }
+
+    pub fn average_match_length(&self) -> f64 {
+        if self.references == 0 {
+            0.0
+        } else {
+            self.match_bytes as f64 / self.references as f64
+        }
+    }
+
+    // Single line JSON object
+    pub fn to_json(&self) -> String {
+        let layout = match self.config.layout() {
+            Layout::BitPacked => String::from("\"bit-packed\""),
+            Layout::FlagGroups(flag_count) => format!("\"flag-groups-{}\"", flag_count),
+        };
+        let dictionary_id = self
+            .dictionary_id
+            .map_or(String::from("null"), |id| id.to_string());
+        let histogram: Vec<String> = self
+            .distance_histogram
+            .iter()
+            .enumerate()
+            .map(|(bucket, count)| {
+                format!(
+                    "{{\"min\":{},\"max\":{},\"count\":{}}}",
+                    1u64 << bucket,
+                    (2u64 << bucket) - 1,
+                    count
+                )
+            })
+            .collect();
+        format!(
+            concat!(
+                "{{\"history_addr_bits\":{},\"match_length_bits\":{},\"threshold\":{},",
+                "\"layout\":{},\"dictionary_id\":{},\"compressed_size\":{},",
+                "\"uncompressed_size\":{},\"ratio\":{:.4},\"literals\":{},\"references\":{},",
+                "\"average_match_length\":{:.2},\"distance_histogram\":[{}]}}"
+            ),
+            self.config.history_addr_nbits(),
+            self.config.match_length_nbits(),
+            self.config.threshold(),
+            layout,
+            dictionary_id,
+            self.compressed_size,
+            self.uncompressed_size,
+            self.ratio(),
+            self.literals,
+            self.references,
+            self.average_match_length(),
+            histogram.join(",")
+        )
+    }
+}
+
+// Counts bytes read through it
+struct CountingReader<R: Read> {
+    reader: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+// Reads a whole stream. Streams encoded with a preset dictionary need the `dictionary`, since
+// positions of references depend on it's length.
+pub fn analyze<R: Read>(reader: R, dictionary: Option<&[u8]>) -> Result<StreamInfo> {
+    let mut counter = CountingReader { reader, count: 0 };
+    let mut tokens = match dictionary {
+        Some(dictionary) => TokenReader::with_dictionary(&mut counter, dictionary)?,
+        None => TokenReader::new(&mut counter)?,
+    };
+    let config = *tokens.config();
+    let mut info = StreamInfo {
+        config,
+        dictionary_id: tokens.codec().dictionary_id(),
+        compressed_size: 0,
+        uncompressed_size: 0,
+        literals: 0,
+        references: 0,
+        match_bytes: 0,
+        distance_histogram: vec![0; usize::from(config.history_addr_nbits()) + 1],
+    };
+    let preset_len = tokens.codec().preset_history().len() as u64;
+    let history_size = config.history_size() as u64;
+    while let Some(token) = tokens.read_token()? {
+        match token {
+            Token::Literal(_) => info.literals += 1,
+            Token::Match { position, length } => {
+                let history_len = u64::min(preset_len + info.uncompressed_size, history_size);
+                let distance = history_len - position as u64;
+                info.distance_histogram[63 - distance.leading_zeros() as usize] += 1;
+                info.references += 1;
+                info.match_bytes += length as u64;
+            }
+        }
+        info.uncompressed_size += token.decoded_len() as u64;
+    }
+    drop(tokens);
+    info.compressed_size = counter.count;
+    Ok(info)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encoder::Encoder;
+
+    #[test]
+    fn counts_records() {
+        let data = std::fs::read("test-files/fields.c").unwrap();
+        let mut archive = Vec::new();
+        Encoder::new(10, 4, 0)
+            .encode(&mut data.as_slice(), &mut archive)
+            .unwrap();
+        let tokens: Vec<Token> = parse_tokens(archive.as_slice())
+            .collect::<Result<_>>()
+            .unwrap();
+
+        let info = analyze(archive.as_slice(), None).unwrap();
+        assert_eq!(info.config, Config::new(10, 4, 0));
+        assert_eq!(info.compressed_size, archive.len() as u64);
+        assert_eq!(info.uncompressed_size, data.len() as u64);
+        assert_eq!(info.literals + info.references, tokens.len() as u64);
+        assert_eq!(info.literals + info.match_bytes, data.len() as u64);
+        assert_eq!(info.distance_histogram.iter().sum::<u64>(), info.references);
+        assert!(info.to_json().starts_with("{\"history_addr_bits\":10,"));
+    }
+}
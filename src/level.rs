@@ -0,0 +1,125 @@
+// Compression levels, from the fastest (1) to the best compression (9), like the ones of gzip.
+// Every level is a preset of history and match length sizes and a match finder. Higher levels
+// use bigger histories, check more candidates per position and use lazy matching, which makes
+// them slower. Levels are not stored in the stream, decoding speed doesn't depend on them.
+use super::match_finder::{HashChain, MatchFinder};
+use super::Config;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    L1,
+    L2,
+    L3,
+    L4,
+    L5,
+    // Same default as gzip
+    #[default]
+    L6,
+    L7,
+    L8,
+    L9,
+}
+
+struct Preset {
+    history_addr_nbits: u8,
+    match_length_nbits: u8,
+    max_chain: usize,
+    lazy: bool,
+}
+
+const fn preset(
+    history_addr_nbits: u8,
+    match_length_nbits: u8,
+    max_chain: usize,
+    lazy: bool,
+) -> Preset {
+    Preset {
+        history_addr_nbits,
+        match_length_nbits,
+        max_chain,
+        lazy,
+    }
+}
+
+// Indexed by level - 1
+const PRESETS: [Preset; 9] = [
+    preset(12, 4, 4, false),
+    preset(13, 4, 8, false),
+    preset(14, 4, 16, false),
+    preset(14, 4, 32, true),
+    preset(15, 4, 64, true),
+    preset(15, 4, 128, true),
+    preset(16, 4, 256, true),
+    preset(16, 5, 1024, true),
+    preset(16, 5, 4096, true),
+];
+
+impl Level {
+    pub const ALL: [Level; 9] = [
+        Level::L1,
+        Level::L2,
+        Level::L3,
+        Level::L4,
+        Level::L5,
+        Level::L6,
+        Level::L7,
+        Level::L8,
+        Level::L9,
+    ];
+
+    // Returns None if `number` is not in range of [1, 9]
+    pub fn from_number(number: u8) -> Option<Level> {
+        Level::ALL.get(usize::from(number).wrapping_sub(1)).copied()
+    }
+
+    pub fn number(self) -> u8 {
+        self as u8 + 1
+    }
+
+    fn preset(self) -> &'static Preset {
+        &PRESETS[self as usize]
+    }
+
+    pub fn config(self) -> Config {
+        let preset = self.preset();
+        Config::new(preset.history_addr_nbits, preset.match_length_nbits, 0)
+    }
+
+    // Match finder of this level for a history of `history_size` bytes (it doesn't have to be
+    // the one of `config`, sizes may be chosen in some other way)
+    pub fn match_finder(self, history_size: usize) -> Box<dyn MatchFinder> {
+        let preset = self.preset();
+        let finder = HashChain::new(history_size, preset.max_chain);
+        if preset.lazy {
+            Box::new(finder.with_lazy_matching())
+        } else {
+            Box::new(finder)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decoder;
+    use crate::encoder::Encoder;
+
+    #[test]
+    fn higher_levels_compress_better() {
+        let data = std::fs::read("test-files/lcet10.txt").unwrap();
+        let mut sizes = Vec::new();
+        for &level in &[Level::L1, Level::L6, Level::L9] {
+            let mut archive = Vec::new();
+            Encoder::with_level(level)
+                .encode(&mut data.as_slice(), &mut archive)
+                .unwrap();
+            let mut decoded = Vec::new();
+            decoder::decode(archive.as_slice(), &mut decoded).unwrap();
+            assert_eq!(decoded, data);
+            sizes.push(archive.len());
+        }
+        assert!(sizes[0] > sizes[1] && sizes[1] > sizes[2]);
+        assert_eq!(Level::from_number(9), Some(Level::L9));
+        assert_eq!(Level::from_number(0), None);
+    }
+}
@@ -12,10 +12,128 @@ pub const MIN_MATCH_LENGTH_BITS: usize = 2;
 pub const RECORD_TYPE_REFERENCE: bool = false;
 pub const RECORD_TYPE_LITERAL: bool = true;
 
+pub mod archive;
+pub mod bit_io;
+pub mod checksum;
+pub mod codec;
+pub mod complzss;
 pub mod decoder;
+pub mod delta;
+pub mod dict;
+pub mod dump;
 pub mod encoder;
 mod history_reader;
+pub mod info;
+pub mod level;
+pub mod lz77;
+pub mod match_finder;
+pub mod nintendo;
+pub mod okumura;
+mod output_window;
 pub mod search;
+pub mod token;
+pub mod tune;
+mod window_buffer;
+
+pub use level::Level;
+pub use token::{parse_tokens, tokenize, tokenize_with, Token};
+
+// How records are laid out in a stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    // Every record starts with a type bit and fields are not byte aligned
+    BitPacked,
+    // Type flags of 8, 16 or 32 records are grouped into a control word which is followed by
+    // the records. Literals and references are whole bytes, so nothing needs bit level reads.
+    FlagGroups(u8),
+}
+
+// Parameters of an encoded stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    history_addr_nbits: u8, // Number of bits used for addressing history
+    match_length_nbits: u8, // Number of bits used for specifying length of a match
+    search_depth: u8, // 0 - longest match, 1 - first match, 2 - longest of the first two matches
+    layout: Layout,
+}
+
+impl Config {
+    pub fn new(history_addr_nbits: u8, match_length_nbits: u8, search_depth: u8) -> Config {
+        assert!(
+            (MIN_HISTORY_ADDR_BITS..=MAX_HISTORY_ADDR_BITS)
+                .contains(&(history_addr_nbits as usize)),
+            "History address size must be in range of [{}, {}] bits",
+            MIN_HISTORY_ADDR_BITS,
+            MAX_HISTORY_ADDR_BITS
+        );
+        assert!(
+            (MIN_MATCH_LENGTH_BITS..=MAX_MATCH_LENGTH_BITS)
+                .contains(&(match_length_nbits as usize)),
+            "Match length size must be in range of [{}, {}] bits",
+            MIN_MATCH_LENGTH_BITS,
+            MAX_MATCH_LENGTH_BITS
+        );
+        assert!(
+            history_addr_nbits > match_length_nbits,
+            "History size has to be bigger than current window size"
+        );
+
+        Config {
+            history_addr_nbits,
+            match_length_nbits,
+            search_depth,
+            layout: Layout::BitPacked,
+        }
+    }
+
+    pub fn with_layout(mut self, layout: Layout) -> Config {
+        if let Layout::FlagGroups(flag_count) = layout {
+            assert!(
+                flag_count == 8 || flag_count == 16 || flag_count == 32,
+                "Control word has to be 8, 16 or 32 bits long"
+            );
+        }
+        self.layout = layout;
+        self
+    }
+
+    pub fn history_addr_nbits(&self) -> u8 {
+        self.history_addr_nbits
+    }
+
+    pub fn match_length_nbits(&self) -> u8 {
+        self.match_length_nbits
+    }
+
+    pub fn search_depth(&self) -> u8 {
+        self.search_depth
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    // Minimum length of a match that is worth encoding as a reference
+    pub fn threshold(&self) -> usize {
+        calc_threshold(
+            self.history_addr_nbits as usize,
+            self.match_length_nbits as usize,
+        )
+    }
+
+    pub fn history_size(&self) -> usize {
+        // Asserting that window size does not break the limits (that's why using this type for pow function)
+        HistoryAddress::pow(2, self.history_addr_nbits as u32) as usize
+    }
+
+    // Size of the window matches are searched for. It is also the size of the raw beginning of a stream.
+    pub fn current_window_size(&self) -> usize {
+        let size = MatchLength::pow(2, self.match_length_nbits as u32) as usize;
+        // Increasing window size by threshold, because we won't be encoding matches shorter than threshold
+        // Decreasing window size by one because we cannot encode the largest possible length of 2^n with n bits.
+        size + self.threshold() - 1
+    }
+}
 
 fn calc_threshold(history_addr_nbits: usize, match_len_nbits: usize) -> usize {
     let record_1_size = 1 + history_addr_nbits + match_len_nbits;
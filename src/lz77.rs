@@ -0,0 +1,376 @@
+// Classic LZ77 output made of (offset, length, next byte) triples, for comparison with LZSS.
+// Every step emits a triple, even when no match is found (offset and length are 0 then), and
+// the byte following a match is always part of the same triple. Matches are found with
+// `search::best_match`, so results can be compared with the format of this crate directly.
+//
+// Offsets count back from the end of history, so history is 2^history_addr_nbits - 1 bytes long.
+// Lengths are smaller than 2^match_length_nbits.
+//
+// Binary form: the same header as the format of this crate (5 bits of history address size,
+// 4 bits of match length size), then triples with fields of these sizes and an 8 bit byte,
+// MSB first with no byte alignment. Padding in the last byte is shorter than any triple.
+//
+// Text form: a header line "lz77 <history bits> <length bits>", then one "(offset,length,'byte')"
+// line per triple. Bytes are escaped like `std::ascii::escape_default` does.
+use super::bit_io::{BitReader, BitWriter};
+use super::history_reader::HistoryReader;
+use super::output_window::OutputWindow;
+use super::search;
+use super::*;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TripleFormat {
+    Binary,
+    Text,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Triple {
+    // Distance back from the end of history, 0 if there is no match
+    pub offset: usize,
+    pub length: usize,
+    pub next: u8,
+}
+
+impl Triple {
+    pub fn to_text(&self) -> String {
+        let next: String = std::ascii::escape_default(self.next)
+            .map(char::from)
+            .collect();
+        format!("({},{},'{}')", self.offset, self.length, next)
+    }
+
+    pub fn from_text(line: &str) -> Result<Triple> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "Invalid triple");
+        let line = line.trim_end();
+        if !line.starts_with('(') || !line.ends_with("')") {
+            return Err(invalid());
+        }
+        let mut fields = line[1..line.len() - 2].splitn(3, ',');
+        let offset = fields.next().and_then(|f| f.parse().ok());
+        let length = fields.next().and_then(|f| f.parse().ok());
+        let next = fields
+            .next()
+            .and_then(|f| f.strip_prefix('\''))
+            .and_then(unescape);
+        match (offset, length, next) {
+            (Some(offset), Some(length), Some(next)) => Ok(Triple {
+                offset,
+                length,
+                next,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+// Reverse of `std::ascii::escape_default` for a single byte
+fn unescape(text: &str) -> Option<u8> {
+    let bytes = text.as_bytes();
+    match bytes {
+        [byte] if *byte != b'\\' => Some(*byte),
+        [b'\\', b't'] => Some(b'\t'),
+        [b'\\', b'r'] => Some(b'\r'),
+        [b'\\', b'n'] => Some(b'\n'),
+        [b'\\', byte @ b'\''] | [b'\\', byte @ b'"'] | [b'\\', byte @ b'\\'] => Some(*byte),
+        [b'\\', b'x', _, _] => u8::from_str_radix(&text[2..], 16).ok(),
+        _ => None,
+    }
+}
+
+fn history_size(config: &Config) -> usize {
+    config.history_size() - 1
+}
+
+fn window_size(config: &Config) -> usize {
+    // Longest match and the byte following it
+    1 << config.match_length_nbits()
+}
+
+pub fn encode<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    config: &Config,
+    format: TripleFormat,
+) -> Result<()> {
+    let mut output = TripleWriter::new(writer, config, format)?;
+    let mut reader = HistoryReader::new(reader, history_size(config), window_size(config), &[], 0)?;
+    let (mut history, mut window) = reader.current();
+    while !window.is_empty() {
+        // Last byte of input can only be the next byte of a triple
+        let (position, length) = if window.len() > 1 {
+            search::best_match(
+                history,
+                &window[..window.len() - 1],
+                1,
+                config.search_depth() as usize,
+            )
+        } else {
+            (0, 0)
+        };
+        let offset = if length > 0 {
+            history.len() - position
+        } else {
+            0
+        };
+        output.write(Triple {
+            offset,
+            length,
+            next: window[length],
+        })?;
+        let new = reader.next(length + 1)?;
+        history = new.0;
+        window = new.1;
+    }
+    output.finish()
+}
+
+pub fn decode<R: Read, W: Write>(reader: R, writer: W, format: TripleFormat) -> Result<()> {
+    let mut triples = TripleReader::new(reader, format)?;
+    let mut output = OutputWindow::with_history(writer, history_size(&triples.config), &[]);
+    while let Some(triple) = triples.read()? {
+        if triple.length > 0 {
+            if triple.offset == 0 || triple.offset > output.history_len() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Invalid triple: offset points outside of history",
+                ));
+            }
+            output.copy_match(output.history_len() - triple.offset, triple.length)?;
+        }
+        output.push(triple.next)?;
+    }
+    output.flush()
+}
+
+struct TripleWriter<W: Write> {
+    bw: BitWriter<W>,
+    config: Config,
+    format: TripleFormat,
+}
+
+impl<W: Write> TripleWriter<W> {
+    fn new(writer: W, config: &Config, format: TripleFormat) -> Result<TripleWriter<W>> {
+        let mut bw = BitWriter::new(writer);
+        match format {
+            TripleFormat::Binary => {
+                bw.write_bits(
+                    u32::from(config.history_addr_nbits()),
+                    BITS_FOR_HISTORY_ADDR_NBTIS,
+                )?;
+                bw.write_bits(
+                    u32::from(config.match_length_nbits()),
+                    BITS_FOR_MATCH_LENGTH_NBITS,
+                )?;
+            }
+            TripleFormat::Text => {
+                let header = format!(
+                    "lz77 {} {}\n",
+                    config.history_addr_nbits(),
+                    config.match_length_nbits()
+                );
+                bw.write_bytes(header.as_bytes())?;
+            }
+        }
+        Ok(TripleWriter {
+            bw,
+            config: *config,
+            format,
+        })
+    }
+
+    fn write(&mut self, triple: Triple) -> Result<()> {
+        match self.format {
+            TripleFormat::Binary => {
+                self.bw.write_bits(
+                    triple.offset as u32,
+                    self.config.history_addr_nbits() as usize,
+                )?;
+                self.bw.write_bits(
+                    triple.length as u32,
+                    self.config.match_length_nbits() as usize,
+                )?;
+                self.bw.write_byte(triple.next)
+            }
+            TripleFormat::Text => {
+                let line = triple.to_text() + "\n";
+                self.bw.write_bytes(line.as_bytes())
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.bw.pad_to_byte()?;
+        self.bw.flush()
+    }
+}
+
+enum TripleSource<R: Read> {
+    Binary(BitReader<R>),
+    Text(std::io::Lines<BufReader<R>>),
+}
+
+struct TripleReader<R: Read> {
+    source: TripleSource<R>,
+    config: Config,
+}
+
+impl<R: Read> TripleReader<R> {
+    fn new(reader: R, format: TripleFormat) -> Result<TripleReader<R>> {
+        let invalid_header = || Error::new(ErrorKind::InvalidData, "Invalid header");
+        let (source, history_addr_nbits, match_length_nbits) = match format {
+            TripleFormat::Binary => {
+                let mut br = BitReader::new(reader);
+                let history_addr_nbits = br.read_bits(BITS_FOR_HISTORY_ADDR_NBTIS)? as usize;
+                let match_length_nbits = br.read_bits(BITS_FOR_MATCH_LENGTH_NBITS)? as usize;
+                (
+                    TripleSource::Binary(br),
+                    history_addr_nbits,
+                    match_length_nbits,
+                )
+            }
+            TripleFormat::Text => {
+                let mut lines = BufReader::new(reader).lines();
+                let header = lines.next().ok_or_else(invalid_header)??;
+                let fields: Vec<&str> = header.split(' ').collect();
+                match fields[..] {
+                    ["lz77", history, length] => (
+                        TripleSource::Text(lines),
+                        history.parse().map_err(|_| invalid_header())?,
+                        length.parse().map_err(|_| invalid_header())?,
+                    ),
+                    _ => return Err(invalid_header()),
+                }
+            }
+        };
+        if !(MIN_HISTORY_ADDR_BITS..=MAX_HISTORY_ADDR_BITS).contains(&history_addr_nbits)
+            || !(MIN_MATCH_LENGTH_BITS..=MAX_MATCH_LENGTH_BITS).contains(&match_length_nbits)
+            || history_addr_nbits <= match_length_nbits
+        {
+            return Err(invalid_header());
+        }
+        Ok(TripleReader {
+            source,
+            config: Config::new(history_addr_nbits as u8, match_length_nbits as u8, 0),
+        })
+    }
+
+    // Returns None if stream has ended
+    fn read(&mut self) -> Result<Option<Triple>> {
+        match &mut self.source {
+            TripleSource::Binary(br) => {
+                let triple_nbits = self.config.history_addr_nbits() as usize
+                    + self.config.match_length_nbits() as usize
+                    + 8;
+                let available = br.fill(triple_nbits)?;
+                if available >= triple_nbits {
+                    read_binary_triple(br, &self.config).map(Some)
+                } else if available < 8 {
+                    // Only padding of the last byte is left
+                    Ok(None)
+                } else {
+                    Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "Stream ended in the middle of a triple",
+                    ))
+                }
+            }
+            TripleSource::Text(lines) => match lines.next() {
+                Some(line) => Triple::from_text(&line?).map(Some),
+                None => Ok(None),
+            },
+        }
+    }
+}
+
+fn read_binary_triple<R: Read>(br: &mut BitReader<R>, config: &Config) -> Result<Triple> {
+    let offset = br.read_bits(config.history_addr_nbits() as usize)? as usize;
+    let length = br.read_bits(config.match_length_nbits() as usize)? as usize;
+    Ok(Triple {
+        offset,
+        length,
+        next: br.read_byte()?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn text_triples() {
+        let mut archive = Vec::new();
+        encode(
+            &mut &b"abcabcd\n"[..],
+            &mut archive,
+            &Config::new(12, 4, 0),
+            TripleFormat::Text,
+        )
+        .unwrap();
+        let expected = "lz77 12 4\n(0,0,'a')\n(0,0,'b')\n(0,0,'c')\n(3,3,'d')\n(0,0,'\\n')\n";
+        assert_eq!(String::from_utf8(archive.clone()).unwrap(), expected);
+
+        let mut decoded = Vec::new();
+        decode(archive.as_slice(), &mut decoded, TripleFormat::Text).unwrap();
+        assert_eq!(decoded, b"abcabcd\n");
+    }
+
+    #[test]
+    fn escaped_bytes_round_trip() {
+        for byte in 0..=255u8 {
+            let triple = Triple {
+                offset: 1,
+                length: 2,
+                next: byte,
+            };
+            assert_eq!(Triple::from_text(&triple.to_text()).unwrap(), triple);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = std::fs::read("test-files/fields.c").unwrap();
+        for &format in &[TripleFormat::Binary, TripleFormat::Text] {
+            let mut archive = Vec::new();
+            encode(
+                &mut data.as_slice(),
+                &mut archive,
+                &Config::new(10, 4, 0),
+                format,
+            )
+            .unwrap();
+            let mut decoded = Vec::new();
+            decode(archive.as_slice(), &mut decoded, format).unwrap();
+            assert_eq!(decoded, data);
+        }
+    }
+
+    #[test]
+    fn truncated_binary_triples_fail() {
+        let data = std::fs::read("test-files/fields.c").unwrap();
+        let config = Config::new(10, 4, 0);
+        let mut archive = Vec::new();
+        encode(
+            &mut data.as_slice(),
+            &mut archive,
+            &config,
+            TripleFormat::Binary,
+        )
+        .unwrap();
+        let triple_nbits = 10 + 4 + 8;
+        for cut in 1..=2 * triple_nbits {
+            let len = archive.len() - cut;
+            let mut decoded = Vec::new();
+            let result = decode(&archive[..len], &mut decoded, TripleFormat::Binary);
+            // Only when the part of the last triple left is shorter than a byte it can't be
+            // told apart from padding
+            if (len * 8 - 9) % triple_nbits >= 8 {
+                assert_eq!(result.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+            } else {
+                result.unwrap();
+                assert!(data.starts_with(&decoded));
+            }
+        }
+    }
+}
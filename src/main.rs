@@ -1,123 +1,1519 @@
 #[macro_use]
 extern crate clap;
-use clap::AppSettings;
-use env_logger;
-use lzss::decoder;
-use lzss::encoder::Encoder;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Write};
+use clap::{AppSettings, Arg, ArgGroup};
 use log::debug;
+use lzss::archive::{self, ArchiveReader, ArchiveWriter, Entry};
+use lzss::bit_io::BitReader;
+use lzss::checksum::{checksum_mismatch, is_checksum_mismatch};
+use lzss::codec::LzssCodec;
+use lzss::decoder::Decoder;
+use lzss::dump::{self, DumpFormat};
+use lzss::encoder::Encoder;
+use lzss::lz77::{self, TripleFormat};
+use lzss::nintendo::{self, Variant};
+use lzss::{complzss, decoder, delta, dict, info, okumura, tune, Config, Layout, Level};
+use lzss::{
+    MAX_HISTORY_ADDR_BITS, MAX_MATCH_LENGTH_BITS, MIN_HISTORY_ADDR_BITS, MIN_MATCH_LENGTH_BITS,
+};
+use std::collections::HashSet;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File, FileTimes, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Cursor, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, UNIX_EPOCH};
+
+// Names of the -1 .. -9 options
+const LEVELS: [&str; 9] = ["1", "2", "3", "4", "5", "6", "7", "8", "9"];
 
 fn main() {
     env_logger::builder()
         .format(|buf, record| writeln!(buf, "{}", record.args()))
         .init();
 
+    // Macro doesn't take digits as option names. Only the ends of the range are listed in help.
+    let level_args: Vec<Arg> = LEVELS
+        .iter()
+        .map(|&level| {
+            let arg = Arg::with_name(level).short(level);
+            match level {
+                "1" => arg.help(
+                    "Fastest compression. -1 .. -9 choose sizes and match finder of a compression level (-s, -c and -d are ignored)",
+                ),
+                "9" => arg.help("Best compression"),
+                _ => arg.hidden(true),
+            }
+        })
+        .collect();
+
     let arg_matches = clap_app!(lzss =>
        (about: "LZSS compression, decompriossion")
        (version: "0.1.0")
+       (after_help: "Without a subcommand files are handled like gzip does: `lzss [-d] [OPTIONS] [FILE]` encodes FILE into FILE.lzss (or decodes it with -d) and removes FILE, unless -k is given. Subcommands never remove their input.\n\nExit status: 0 - success, 1 - I/O error, 2 - usage error, 3 - corrupt data, 4 - checksum mismatch. If several files fail, the highest one is returned.")
        (@subcommand encode =>
             (about: "Encode using LZSS algorithm")
-            (@arg FILE: +required "File to encode")
-            (@arg ARCHIVE_PATH: +required "Resulting archive path")
-            (@arg history_size: -s +takes_value default_value("12") "History window address size in bits")
-            (@arg match_length_size: -c +takes_value default_value("4") "Match record length in bits. Determines size of the current window as well.")
-            (@arg search_depth: -d +takes_value default_value("0") 
+            (@arg FILE: +multiple "FILE [ARCHIVE_PATH]: file to encode (- or nothing for standard input) and resulting archive path (- for standard output, FILE with the suffix appended by default). Every path is a file to encode if there are more than two of them or with -r or --multiple.")
+            (@arg recursive: -r --recursive "Encode every file in directories and their subdirectories")
+            (@arg multiple: --multiple "Every path is a file to encode")
+            (@arg stdout: -p --stdout "Write archives to standard output (ARCHIVE_PATH is ignored). -c without a subcommand.")
+            (@arg history_size: -s +takes_value default_value("12") {is_history_size} "History window address size in bits")
+            (@arg match_length_size: -c +takes_value default_value("4") {is_match_length_size} "Match record length in bits. Determines size of the current window as well.")
+            (@arg search_depth: -d +takes_value default_value("0") {is_search_depth}
                 "Search depth for searching history for matches. 0 - all matches are found and the longest chosen. 1 - The first match is used")
+            (@arg flag_groups: -g --("flag-groups") +takes_value {is_flag_count}
+                "Group type flags of this many records into a control word and byte align records (faster decoding)")
+            (@arg auto: -a --auto "Choose history and match length sizes by trial encoding the beginning of input (-s and -c are ignored)")
+            (@arg dictionary: -D --dictionary +takes_value "Preset dictionary file (format of this crate only)")
             (@arg overwrite: -o --overwrite "Overwrite existing file")
+            (@arg force: -f --force "Overwrite existing file, encode files which already have the suffix, write to a terminal")
+            (@arg keep: -k --keep "Keep FILE when the archive name is derived from it (without a subcommand it's removed by default)")
+            (@arg gzip_style: --("gzip-style") +hidden "Set for the command line without a subcommand")
+            (@arg suffix: -S --suffix +takes_value default_value(".lzss") "Suffix of archive names")
+            (@arg format: -F --format +takes_value possible_value[lzss okumura lz10 lz11 complzss lz77 lz77text] default_value("lzss")
+                "Stream format. okumura - format of LZSS.C by Haruhiko Okumura, lz10/lz11 - GBA/DS BIOS LZ77 formats, complzss - Apple kernelcache container (other encoding options are ignored for these). lz77/lz77text - classic LZ77 triples in binary/text form")
+            (@arg vram_safe: --("vram-safe") "Do not use matches at distance 1 (lz10/lz11 data decompressed straight to VRAM)")
+            (args: &level_args)
+            (group: ArgGroup::with_name("level").args(&LEVELS))
        )
        (@subcommand decode =>
             (about: "Decode file encoded with this program")
-            (@arg ARCHIVE: +required "File to decode")
-            (@arg FILE_PATH: +required "Resulting file path")
+            (@arg ARCHIVE: +multiple "ARCHIVE [FILE_PATH]: file to decode (- or nothing for standard input) and resulting file path (- for standard output, ARCHIVE without the suffix by default). Every path is a file to decode if there are more than two of them or with -r or --multiple.")
+            (@arg recursive: -r --recursive "Decode every file with the suffix in directories and their subdirectories")
+            (@arg multiple: --multiple "Every path is a file to decode")
+            (@arg stdout: -p --stdout "Write decoded files to standard output (FILE_PATH is ignored). -c without a subcommand.")
             (@arg overwrite: -o "Overwrite existing file")
+            (@arg force: -f --force "Overwrite existing file")
+            (@arg keep: -k --keep "Keep ARCHIVE when the file name is derived from it (without a subcommand it's removed by default)")
+            (@arg gzip_style: --("gzip-style") +hidden "Set for the command line without a subcommand")
+            (@arg suffix: -S --suffix +takes_value default_value(".lzss") "Suffix of archive names")
+            (@arg dictionary: -D --dictionary +takes_value "Preset dictionary the file was encoded with")
+            (@arg format: -F --format +takes_value possible_value[lzss okumura lz10 lz11 complzss lz77 lz77text] default_value("lzss")
+                "Stream format. lz10 and lz11 are both recognized from the header")
+       )
+       (@subcommand train =>
+            (about: "Build a preset dictionary from sample files")
+            (@arg DICTIONARY: +required "Resulting dictionary path")
+            (@arg SAMPLES: +required +multiple "Sample files")
+            (@arg history_size: -s +takes_value default_value("12") {is_history_size} "History window address size in bits. Dictionary is as big as the history.")
+            (@arg size: --size +takes_value {is_size} "Dictionary size in bytes (instead of history size)")
+            (@arg overwrite: -o --overwrite "Overwrite existing file")
+       )
+       (@subcommand diff =>
+            (about: "Encode a new version of a file as a patch against the old one")
+            (@arg OLD: +required "Old version of the file")
+            (@arg NEW: +required "New version of the file")
+            (@arg PATCH: +required "Resulting patch path")
+            (@arg overwrite: -o --overwrite "Overwrite existing file")
+       )
+       (@subcommand patch =>
+            (about: "Apply a patch made with diff")
+            (@arg OLD: +required "Old version of the file")
+            (@arg PATCH: +required "Patch")
+            (@arg NEW: +required "Resulting file path")
+            (@arg overwrite: -o --overwrite "Overwrite existing file")
+       )
+       (@subcommand test =>
+            (about: "Check encoded files by decoding them. Checksums of complzss and lzsa and sizes of lz10 and lz11 are verified too. Plain lzss, okumura and lz77 streams have neither, so they are only checked to decode and truncation may go unnoticed")
+            (@arg ARCHIVE: +required +multiple "Files to check, - for standard input")
+            (@arg dictionary: -D --dictionary +takes_value "Preset dictionary the files were encoded with")
+            (@arg format: -F --format +takes_value possible_value[lzss okumura lz10 lz11 complzss lz77 lz77text lzsa] default_value("lzss")
+                "Stream format. lzsa archives are recognized from the header with the default format as well")
+       )
+       (@subcommand info =>
+            (about: "Show header and statistics of records of a file encoded with this program")
+            (@arg ARCHIVE: +required "File to inspect, - for standard input")
+            (@arg dictionary: -D --dictionary +takes_value "Preset dictionary the file was encoded with")
+            (@arg json: --json "Print statistics as a JSON object")
+       )
+       (@subcommand dump =>
+            (about: "List records of a file encoded with this program: bit offset, output offset, type, position and length, and the bytes they expand to")
+            (@arg ARCHIVE: +required "File to dump, - for standard input")
+            (@arg dictionary: -D --dictionary +takes_value "Preset dictionary the file was encoded with")
+            (@arg json: --json "Print records as JSON")
+       )
+       (@subcommand archive =>
+            (about: "Multi-file archives (.lzsa)")
+            (@setting SubcommandRequiredElseHelp)
+            (@subcommand create =>
+                (about: "Create an archive from files and directories")
+                (@arg ARCHIVE: +required "Resulting archive path, - for standard output")
+                (@arg PATHS: +required +multiple "Files and directories to add")
+                (@arg overwrite: -o --overwrite "Overwrite existing archive")
+                (args: &level_args)
+                (group: ArgGroup::with_name("level").args(&LEVELS))
+            )
+            (@subcommand list =>
+                (about: "List entries of an archive")
+                (@arg ARCHIVE: +required "Archive, - for standard input")
+            )
+            (@subcommand extract =>
+                (about: "Extract entries of an archive")
+                (@arg ARCHIVE: +required "Archive, - for standard input")
+                (@arg PATHS: +multiple "Extract only these entries (and entries in these directories)")
+                (@arg directory: -C --directory +takes_value default_value(".") "Directory to extract to")
+                (@arg overwrite: -o --overwrite "Overwrite existing files")
+            )
        )
-    ).setting(AppSettings::ArgRequiredElseHelp).get_matches();
+    ).setting(AppSettings::ArgRequiredElseHelp).get_matches_from_safe(gzip_style_args(env::args_os()));
+    let arg_matches = match arg_matches {
+        Ok(arg_matches) => arg_matches,
+        Err(err) if err.use_stderr() => {
+            eprintln!("{}", err.message);
+            process::exit(EXIT_USAGE);
+        }
+        // Help and version
+        Err(err) => err.exit(),
+    };
 
-    let subcommand_str = arg_matches
-        .subcommand_name()
-        .expect("Subcommand is required");
-    if subcommand_str == "encode" {
-        let sub_arg_matches = arg_matches.subcommand_matches("encode").unwrap();
+    let status = match arg_matches.subcommand() {
+        ("encode", Some(matches)) => process_files(matches, false),
+        ("decode", Some(matches)) => process_files(matches, true),
+        ("train", Some(matches)) => train_dictionary(matches),
+        ("diff", Some(matches)) => diff_or_patch(matches, true),
+        ("patch", Some(matches)) => diff_or_patch(matches, false),
+        ("test", Some(matches)) => test_files(matches),
+        ("info", Some(matches)) => show_info(matches),
+        ("dump", Some(matches)) => dump_records(matches),
+        ("archive", Some(matches)) => match matches.subcommand() {
+            ("create", Some(matches)) => create_archive(matches),
+            ("list", Some(matches)) => list_archive(matches),
+            ("extract", Some(matches)) => extract_archive(matches),
+            _ => unreachable!("Subcommand is required"),
+        },
+        _ => unreachable!("Subcommand is required"),
+    };
+    process::exit(status);
+}
 
-        let filepath = sub_arg_matches.value_of("FILE").unwrap();
-        let source_file = File::open(filepath).unwrap();
-        let mut buff_reader = BufReader::new(source_file);
+// Exit codes. If several files fail, the highest code of them is returned.
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_IO_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_CORRUPT_DATA: i32 = 3;
+const EXIT_CHECKSUM_MISMATCH: i32 = 4;
 
-        let filepath = sub_arg_matches.value_of("ARCHIVE_PATH").unwrap();
-        let dest_file = if sub_arg_matches.is_present("overwrite") {
-            OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(filepath)
-                .unwrap()
-        } else {
-            OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(filepath)
-                .unwrap()
-        };
-        // let mut buff_writer = dest_file;
-        let mut buff_writer = BufWriter::new(dest_file);
+fn exit_code(err: &io::Error) -> i32 {
+    if is_checksum_mismatch(err) {
+        EXIT_CHECKSUM_MISMATCH
+    } else {
+        match err.kind() {
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => EXIT_CORRUPT_DATA,
+            io::ErrorKind::InvalidInput => EXIT_USAGE,
+            _ => EXIT_IO_ERROR,
+        }
+    }
+}
 
-        let mut encoder = Encoder::new(
-            sub_arg_matches
+// Prints an error of a file and returns it's exit code
+fn report(path: &str, err: &io::Error) -> i32 {
+    eprintln!("lzss: {}: {}", path, err);
+    exit_code(err)
+}
+
+// Preset dictionary given with -D. Errors are reported and their exit code is returned.
+fn read_dictionary(arg_matches: &clap::ArgMatches) -> Result<Option<Vec<u8>>, i32> {
+    match arg_matches.value_of("dictionary") {
+        Some(path) => fs::read(path).map(Some).map_err(|err| report(path, &err)),
+        None => Ok(None),
+    }
+}
+
+fn train_dictionary(arg_matches: &clap::ArgMatches) -> i32 {
+    let mut samples = Vec::new();
+    for path in arg_matches.values_of("SAMPLES").unwrap() {
+        match fs::read(path) {
+            Ok(sample) => samples.push(sample),
+            Err(err) => return report(path, &err),
+        }
+    }
+    // Both are validated by clap
+    let size = match arg_matches.value_of("size") {
+        Some(size) => size.parse().unwrap(),
+        None => {
+            let history_size: u32 = arg_matches
                 .value_of("history_size")
                 .unwrap()
                 .parse()
-                .expect("Unable to parse history_size"),
-            sub_arg_matches
-                .value_of("match_length_size")
-                .unwrap()
-                .parse()
-                .expect("Unable to parse current_size"),
-            sub_arg_matches
-                .value_of("search_depth")
-                .unwrap()
-                .parse()
-                .expect("Unable to parse search_depth"),
+                .unwrap();
+            1 << history_size
+        }
+    };
+    let samples: Vec<&[u8]> = samples.iter().map(|sample| sample.as_slice()).collect();
+    let dictionary = dict::train(&samples, size);
+
+    let path = arg_matches.value_of("DICTIONARY").unwrap();
+    let overwrite = arg_matches.is_present("overwrite");
+    match write_output(Some(path), overwrite, |output| {
+        output.write_all(&dictionary)
+    }) {
+        Ok(()) => EXIT_SUCCESS,
+        Err(err) => err.report(path, path),
+    }
+}
+
+fn diff_or_patch(arg_matches: &clap::ArgMatches, diff: bool) -> i32 {
+    let old_path = arg_matches.value_of("OLD").unwrap();
+    let old = match fs::read(old_path) {
+        Ok(old) => old,
+        Err(err) => return report(old_path, &err),
+    };
+    let (source, dest) = if diff {
+        ("NEW", "PATCH")
+    } else {
+        ("PATCH", "NEW")
+    };
+    let source = arg_matches.value_of(source).unwrap();
+    if !diff {
+        // Header of the patch tells whether it was made against OLD, which is checked before
+        // any output is written
+        let header = File::open(source)
+            .and_then(|file| LzssCodec::read_header(&mut BitReader::new(BufReader::new(file))));
+        match header {
+            Ok(mut codec) => {
+                if let Err(err) = codec.load_dictionary(&old) {
+                    let path = if codec.dictionary_id().is_some() {
+                        old_path
+                    } else {
+                        source
+                    };
+                    return report(path, &err);
+                }
+            }
+            Err(err) => return report(source, &err),
+        }
+    }
+    let mut input = match File::open(source) {
+        Ok(file) => BufReader::new(file),
+        Err(err) => return report(source, &err),
+    };
+
+    let dest = arg_matches.value_of(dest).unwrap();
+    let overwrite = arg_matches.is_present("overwrite");
+    let result = write_output(Some(dest), overwrite, |mut output| {
+        if diff {
+            delta::diff(&old, &mut input, &mut output)
+        } else {
+            delta::patch(&old, &mut input, output)
+        }
+    });
+    match result {
+        Ok(()) => EXIT_SUCCESS,
+        Err(err) => err.report(source, dest),
+    }
+}
+
+// Names of subcommands and options which are handled by clap without a subcommand
+const SUBCOMMANDS: [&str; 13] = [
+    "encode", "decode", "train", "diff", "patch", "test", "info", "dump", "archive", "help", "-h",
+    "--help", "-V",
+];
+
+// Options of encode and decode which take a value. Without a subcommand -d is --decompress
+// and -c is --stdout.
+const SHORT_VALUE_OPTIONS: &str = "sgDSF";
+const LONG_VALUE_OPTIONS: [&str; 4] = ["--flag-groups", "--dictionary", "--suffix", "--format"];
+
+// Command line without a subcommand is handled like gzip does: `lzss FILE...` encodes and
+// `lzss -d FILE...` (or --decompress) decodes, -c writes to standard output. Search depth and
+// match length size can't be set this way.
+// Combined short options (-dk) are split. Hidden --gzip-style marks this mode, in which sources
+// are removed.
+// Without any arguments standard input is encoded, unless it's a terminal (help is shown then).
+fn gzip_style_args<I: IntoIterator<Item = OsString>>(args: I) -> Vec<OsString> {
+    let args: Vec<OsString> = args.into_iter().collect();
+    let is_subcommand =
+        |arg: &OsString| SUBCOMMANDS.iter().any(|&name| arg == name) || arg == "--version";
+    if (args.len() < 2 && io::stdin().is_terminal()) || args.get(1).is_some_and(is_subcommand) {
+        return args;
+    }
+    let (mut args, decompress) = split_options(args);
+    let subcommand = if decompress { "decode" } else { "encode" };
+    args.insert(1, OsString::from(subcommand));
+    args.insert(2, OsString::from("--gzip-style"));
+    args.insert(3, OsString::from("--multiple"));
+    args
+}
+
+// Splits combined short options like -dk into -d -k, takes out -d and --decompress and turns
+// -c into --stdout.
+// Returns whether they were there. Value of an option can be attached to it (-S.z) or be the
+// next argument. Nothing is split after --.
+fn split_options(args: Vec<OsString>) -> (Vec<OsString>, bool) {
+    let mut split = Vec::with_capacity(args.len());
+    let mut args = args.into_iter();
+    // Program name
+    split.extend(args.next());
+    let mut decompress = false;
+    let mut value_next = false;
+    while let Some(arg) = args.next() {
+        let text = match arg.to_str() {
+            Some(text) if !value_next && text.starts_with('-') && text != "-" => text,
+            _ => {
+                value_next = false;
+                split.push(arg);
+                continue;
+            }
+        };
+        if text == "--" {
+            split.push(arg);
+            split.extend(args.by_ref());
+        } else if text == "--decompress" {
+            decompress = true;
+        } else if text.starts_with("--") {
+            value_next = LONG_VALUE_OPTIONS.contains(&text);
+            split.push(arg);
+        } else {
+            for (index, option) in text.char_indices().skip(1) {
+                if option == 'd' {
+                    decompress = true;
+                    continue;
+                } else if option == 'c' {
+                    split.push(OsString::from("--stdout"));
+                    continue;
+                }
+                split.push(OsString::from(format!("-{}", option)));
+                if SHORT_VALUE_OPTIONS.contains(option) {
+                    let value = &text[index + option.len_utf8()..];
+                    if value.is_empty() {
+                        value_next = true;
+                    } else {
+                        split.push(OsString::from(value));
+                    }
+                    break;
+                }
+            }
+        }
+    }
+    (split, decompress)
+}
+
+// Encodes or decodes every input on the command line. Errors are reported for every file
+// and don't stop the rest.
+fn process_files(arg_matches: &clap::ArgMatches, decode: bool) -> i32 {
+    let dictionary = match read_dictionary(arg_matches) {
+        Ok(dictionary) => dictionary,
+        Err(status) => return status,
+    };
+    let input_name = if decode { "ARCHIVE" } else { "FILE" };
+    let paths: Vec<&str> = arg_matches
+        .values_of(input_name)
+        .map_or(Vec::new(), |paths| paths.collect());
+    let recursive = arg_matches.is_present("recursive");
+    let (paths, output) = if recursive || arg_matches.is_present("multiple") || paths.len() > 2 {
+        (paths, None)
+    } else {
+        (
+            paths.iter().take(1).copied().collect(),
+            paths.get(1).copied(),
+        )
+    };
+
+    let suffix = arg_matches.value_of("suffix").unwrap();
+    // Only archives are decoded from directories and archives are not encoded again
+    let wanted = |path: &str| decode == path.ends_with(suffix);
+    let mut status = EXIT_SUCCESS;
+    let mut sources = Vec::new();
+    if paths.is_empty() {
+        // Standard input
+        sources.push(None);
+    }
+    for path in paths {
+        if path != "-" && recursive && Path::new(path).is_dir() {
+            status = status.max(walk_dir(Path::new(path), &wanted, &mut sources));
+        } else {
+            sources.push(Some(path.to_string()).filter(|path| path != "-"));
+        }
+    }
+    let to_stdout = arg_matches.is_present("stdout") || output == Some("-");
+    if to_stdout && !decode && sources.len() > 1 {
+        eprintln!("lzss: Only one file can be encoded to standard output");
+        return EXIT_USAGE;
+    }
+
+    for source in &sources {
+        let source = source.as_deref();
+        let dest = if to_stdout {
+            Dest::Stdout
+        } else {
+            match (output, source) {
+                (Some(output), _) => Dest::File(output.to_string()),
+                (None, Some(_)) => Dest::Derived,
+                (None, None) => Dest::Stdout,
+            }
+        };
+        status = status.max(process_file(
+            arg_matches,
+            decode,
+            dictionary.as_deref(),
+            source,
+            dest,
+        ));
+    }
+    status
+}
+
+// Adds files in `dir` and it's subdirectories for which `wanted` is true to `files`, sorted by
+// name. Symbolic links are skipped. Directories which can't be read are reported and the exit
+// code of them is returned.
+fn walk_dir(dir: &Path, wanted: &dyn Fn(&str) -> bool, files: &mut Vec<Option<String>>) -> i32 {
+    let entries = fs::read_dir(dir).and_then(|entries| entries.collect::<io::Result<Vec<_>>>());
+    let mut entries = match entries {
+        Ok(entries) => entries,
+        Err(err) => return report(&dir.to_string_lossy(), &err),
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+    let mut status = EXIT_SUCCESS;
+    for entry in entries {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => {
+                status = status.max(walk_dir(&path, wanted, files))
+            }
+            Ok(file_type) if file_type.is_file() => match path.to_str() {
+                Some(path) if wanted(path) => files.push(Some(path.to_string())),
+                Some(_) => {}
+                None => {
+                    eprintln!("lzss: {}: Name is not valid UTF-8", path.display());
+                    status = status.max(EXIT_IO_ERROR);
+                }
+            },
+            Ok(_) => {}
+            Err(err) => status = status.max(report(&path.to_string_lossy(), &err)),
+        }
+    }
+    status
+}
+
+// Where output of a file goes
+enum Dest {
+    Stdout,
+    File(String),
+    // Name of the input with the suffix added or removed
+    Derived,
+}
+
+// Encodes or decodes `source` (None for standard input). Errors are reported against the file
+// they concern and their exit code is returned.
+fn process_file(
+    arg_matches: &clap::ArgMatches,
+    decode: bool,
+    dictionary: Option<&[u8]>,
+    source: Option<&str>,
+    dest: Dest,
+) -> i32 {
+    let source_name = source.unwrap_or("(stdin)");
+    let (dest, derived) = match output_path(arg_matches, decode, source, dest) {
+        Ok(dest) => dest,
+        Err(err) => return report(source_name, &err),
+    };
+    let dest_name = dest.as_deref().unwrap_or("(stdout)");
+    let force = arg_matches.is_present("force");
+    if dest.is_none() && !decode && !force && io::stdout().is_terminal() {
+        let err = io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Compressed data is not written to a terminal, use -f to force it",
         );
-        let res = encoder.encode(&mut buff_reader, &mut buff_writer);
-        // debug!("writer buffer: {:#x?}", buff_writer.buffer());
-        // buff_writer.flush().unwrap();
-        if res.is_err() {
-            panic!("Error encoding: {}", res.err().unwrap());
+        return report(dest_name, &err);
+    }
+
+    let overwrite = arg_matches.is_present("overwrite") || force;
+    // Like gzip, but only without a subcommand
+    let remove_source =
+        derived && arg_matches.is_present("gzip_style") && !arg_matches.is_present("keep");
+    let result = open_input(source)
+        .map_err(FileError::Source)
+        .and_then(|input| {
+            let mut buff_reader = BufReader::new(input);
+            write_output(dest.as_deref(), overwrite, |output| {
+                if decode {
+                    decode_stream(arg_matches, dictionary, &mut buff_reader, output)
+                } else {
+                    encode_stream(arg_matches, dictionary, &mut buff_reader, output)
+                }
+            })
+        })
+        .and_then(|()| finish_output(source, dest.as_deref(), remove_source));
+    match result {
+        Ok(()) => EXIT_SUCCESS,
+        Err(err) => err.report(source_name, dest_name),
+    }
+}
+
+// Output path of `source` (None for standard output) and whether it was derived from the name
+// of `source`
+fn output_path(
+    arg_matches: &clap::ArgMatches,
+    decode: bool,
+    source: Option<&str>,
+    dest: Dest,
+) -> io::Result<(Option<String>, bool)> {
+    if source.is_some_and(|path| Path::new(path).is_dir()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Is a directory (use -r to process files in it)",
+        ));
+    }
+    let suffix = arg_matches.value_of("suffix").unwrap();
+    let force = arg_matches.is_present("force");
+    Ok(match (dest, source) {
+        (Dest::Derived, Some(source)) if decode => (Some(original_path(source, suffix)?), true),
+        (Dest::Derived, Some(source)) => (Some(archive_path(source, suffix, force)?), true),
+        (Dest::File(path), _) => (Some(path), false),
+        _ => (None, false),
+    })
+}
+
+fn encode_stream(
+    arg_matches: &clap::ArgMatches,
+    dictionary: Option<&[u8]>,
+    reader: &mut dyn Read,
+    mut writer: &mut dyn Write,
+) -> io::Result<()> {
+    // Each of them is validated by clap, but not how they relate
+    let parse = |name| arg_matches.value_of(name).unwrap().parse().unwrap();
+    let (history_size, match_length_size): (u8, u8) =
+        (parse("history_size"), parse("match_length_size"));
+    let layout = match arg_matches.value_of("flag_groups") {
+        Some(flag_count) => Layout::FlagGroups(flag_count.parse().unwrap()),
+        None => Layout::BitPacked,
+    };
+    let level = selected_level(arg_matches);
+    let config = match level {
+        Some(level) => level.config(),
+        None if history_size <= match_length_size => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "History size (-s) has to be bigger than match length size (-c)",
+            ))
+        }
+        None => Config::new(history_size, match_length_size, parse("search_depth")),
+    };
+    let mut config = config.with_layout(layout);
+    let mut input: Box<dyn Read> = Box::new(reader);
+    if arg_matches.is_present("auto") {
+        let mut sample = Vec::new();
+        input
+            .by_ref()
+            .take(tune::SAMPLE_SIZE as u64)
+            .read_to_end(&mut sample)?;
+        config = tune::choose_config(&sample, config);
+        debug!("Chosen configuration: {:?}", config);
+        input = Box::new(Cursor::new(sample).chain(input));
+    }
+    let mut encoder = match level {
+        Some(level) => {
+            Encoder::with_match_finder(config, level.match_finder(config.history_size()))
         }
-    } else if subcommand_str == "decode" {
-        let sub_arg_matches = arg_matches.subcommand_matches("decode").unwrap();
+        None => Encoder::with_config(config),
+    };
+    if let Some(dictionary) = dictionary {
+        encoder = encoder.with_dictionary(dictionary);
+    }
+    let vram_safe = arg_matches.is_present("vram_safe");
+    match arg_matches.value_of("format").unwrap() {
+        "okumura" => okumura::encode(&mut input, &mut writer),
+        "complzss" => complzss::encode(&mut input, &mut writer),
+        "lz10" => nintendo::encode(&mut input, &mut writer, Variant::Lz10, vram_safe),
+        "lz11" => nintendo::encode(&mut input, &mut writer, Variant::Lz11, vram_safe),
+        "lz77" => lz77::encode(
+            &mut input,
+            &mut writer,
+            encoder.config(),
+            TripleFormat::Binary,
+        ),
+        "lz77text" => lz77::encode(
+            &mut input,
+            &mut writer,
+            encoder.config(),
+            TripleFormat::Text,
+        ),
+        _ => encoder.encode(&mut input, &mut writer),
+    }
+}
 
-        let filepath = sub_arg_matches.value_of("ARCHIVE").unwrap();
-        let source_file = File::open(filepath).unwrap();
-        let mut buff_reader = BufReader::new(source_file);
+fn decode_stream(
+    arg_matches: &clap::ArgMatches,
+    dictionary: Option<&[u8]>,
+    reader: &mut dyn Read,
+    writer: &mut dyn Write,
+) -> io::Result<()> {
+    match arg_matches.value_of("format").unwrap() {
+        "okumura" => okumura::decode(reader, writer),
+        "complzss" => complzss::decode(reader, writer),
+        "lz10" | "lz11" => nintendo::decode(reader, writer),
+        "lz77" => lz77::decode(reader, writer, TripleFormat::Binary),
+        "lz77text" => lz77::decode(reader, writer, TripleFormat::Text),
+        _ => match dictionary {
+            Some(dictionary) => Decoder::with_dictionary(reader, writer, dictionary)?.decode(),
+            None => decoder::decode(reader, writer),
+        },
+    }
+}
 
-        let filepath = sub_arg_matches.value_of("FILE_PATH").unwrap();
-        let dest_file = if sub_arg_matches.is_present("overwrite") {
-            OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(filepath)
-                .unwrap()
+fn archive_path(path: &str, suffix: &str, force: bool) -> io::Result<String> {
+    if suffix.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Suffix of archive names cannot be empty",
+        ));
+    } else if path.ends_with(suffix) && !force {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} already has {} suffix", path, suffix),
+        ));
+    }
+    Ok(format!("{}{}", path, suffix))
+}
+
+fn original_path(path: &str, suffix: &str) -> io::Result<String> {
+    match path.strip_suffix(suffix) {
+        Some(original) if !original.is_empty() && !suffix.is_empty() => Ok(original.to_string()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} doesn't have {} suffix", path, suffix),
+        )),
+    }
+}
+
+// Gives output file permissions and access and modification times of the source file and
+// removes the source if asked. Nothing is done for standard input or output, and devices keep
+// their metadata. Source is never removed if it is the output itself.
+fn finish_output(
+    source: Option<&str>,
+    dest: Option<&str>,
+    remove_source: bool,
+) -> Result<(), FileError> {
+    let (source, dest) = match (source, dest) {
+        (Some(source), Some(dest)) if fs::metadata(dest).map_err(FileError::Dest)?.is_file() => {
+            (source, dest)
+        }
+        _ => return Ok(()),
+    };
+    let metadata = fs::metadata(source).map_err(FileError::Source)?;
+    let times = file_times(&metadata).map_err(FileError::Source)?;
+    OpenOptions::new()
+        .write(true)
+        .open(dest)
+        .and_then(|file| file.set_times(times))
+        .and_then(|()| fs::set_permissions(dest, metadata.permissions()))
+        .map_err(FileError::Dest)?;
+    if remove_source {
+        let same_file = fs::canonicalize(source).map_err(FileError::Source)?
+            == fs::canonicalize(dest).map_err(FileError::Dest)?;
+        if !same_file {
+            fs::remove_file(source).map_err(FileError::Source)?;
+        }
+    }
+    Ok(())
+}
+
+// Access and modification times of a file
+fn file_times(metadata: &fs::Metadata) -> io::Result<FileTimes> {
+    Ok(FileTimes::new()
+        .set_accessed(metadata.accessed()?)
+        .set_modified(metadata.modified()?))
+}
+
+// Level chosen with -1 .. -9
+fn selected_level(arg_matches: &clap::ArgMatches) -> Option<Level> {
+    LEVELS
+        .iter()
+        .position(|&level| arg_matches.is_present(level))
+        .map(|index| Level::ALL[index])
+}
+
+// Adds files to an archive. Files which can't be added are reported and skipped.
+fn create_archive(arg_matches: &clap::ArgMatches) -> i32 {
+    let mut sources = Vec::new();
+    let mut status = EXIT_SUCCESS;
+    for path in arg_matches.values_of("PATHS").unwrap() {
+        if Path::new(path).is_dir() {
+            status = status.max(walk_dir(Path::new(path), &|_| true, &mut sources));
         } else {
+            sources.push(Some(path.to_string()));
+        }
+    }
+
+    let archive_path = Some(arg_matches.value_of("ARCHIVE").unwrap()).filter(|&path| path != "-");
+    let overwrite = arg_matches.is_present("overwrite");
+    let level = selected_level(arg_matches).unwrap_or_default();
+    // Existing archive which is being replaced may be among the sources under another name
+    let archive_file = archive_path.and_then(|path| fs::canonicalize(path).ok());
+    let result = write_output(archive_path, overwrite, |output| {
+        let mut archive = ArchiveWriter::new(output, level)?;
+        let mut added = HashSet::new();
+        for source in sources.iter().flatten() {
+            // Archive may be in one of the directories and paths may be given twice
+            let entry_path = entry_path(source);
+            let is_archive =
+                archive_file.is_some() && fs::canonicalize(source).ok() == archive_file;
+            if is_archive || !added.insert(entry_path.clone()) {
+                continue;
+            }
+            if let Err(err) = add_to_archive(&mut archive, source, &entry_path) {
+                status = status.max(report(source, &err));
+            }
+        }
+        archive.finish().map(|_| ())
+    });
+    if let Err(err) = result {
+        let archive_name = archive_path.unwrap_or("(stdout)");
+        status = status.max(err.report(archive_name, archive_name));
+    }
+    status
+}
+
+// Leading / and . components are dropped, like tar does
+fn entry_path(path: &str) -> String {
+    let components: Vec<&str> = path
+        .split('/')
+        .filter(|&component| !component.is_empty() && component != ".")
+        .collect();
+    components.join("/")
+}
+
+fn add_to_archive<W: Write>(
+    archive: &mut ArchiveWriter<W>,
+    path: &str,
+    entry_path: &str,
+) -> io::Result<()> {
+    let metadata = fs::metadata(path)?;
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let mut file = BufReader::new(File::open(path)?);
+    archive.add(entry_path, file_mode(&metadata), mtime, &mut file)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+#[cfg(unix)]
+fn set_file_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_file_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(path, permissions)
+}
+
+// Decodes every file without writing the output and reports OK or FAILED for each of them. Unlike
+// `gzip -t`, formats without a checksum or size are only checked to decode.
+fn test_files(arg_matches: &clap::ArgMatches) -> i32 {
+    let dictionary = match read_dictionary(arg_matches) {
+        Ok(dictionary) => dictionary,
+        Err(status) => return status,
+    };
+    let mut status = EXIT_SUCCESS;
+    for path in arg_matches.values_of("ARCHIVE").unwrap() {
+        match test_file(arg_matches, dictionary.as_deref(), path) {
+            Ok(()) => println!("{}: OK", path),
+            Err(err) => {
+                println!("{}: FAILED ({})", path, err);
+                status = status.max(exit_code(&err));
+            }
+        }
+    }
+    status
+}
+
+fn test_file(
+    arg_matches: &clap::ArgMatches,
+    dictionary: Option<&[u8]>,
+    path: &str,
+) -> io::Result<()> {
+    let mut input = BufReader::new(open_input(Some(path))?);
+    let format = arg_matches.value_of("format").unwrap();
+    if format == "lzsa" || (format == "lzss" && input.fill_buf()?.starts_with(archive::MAGIC)) {
+        let mut archive = ArchiveReader::new(input)?;
+        while let Some(entry) = archive.next_entry()? {
+            archive.read_data(&entry, io::sink()).map_err(|err| {
+                let message = format!("{}: {}", entry.path, err);
+                if is_checksum_mismatch(&err) {
+                    checksum_mismatch(&message)
+                } else {
+                    io::Error::new(err.kind(), message)
+                }
+            })?;
+        }
+        Ok(())
+    } else {
+        decode_stream(arg_matches, dictionary, &mut input, &mut io::sink())
+    }
+}
+
+fn show_info(arg_matches: &clap::ArgMatches) -> i32 {
+    let dictionary = match read_dictionary(arg_matches) {
+        Ok(dictionary) => dictionary,
+        Err(status) => return status,
+    };
+    let path = arg_matches.value_of("ARCHIVE").unwrap();
+    let result = open_input(Some(path))
+        .and_then(|input| info::analyze(BufReader::new(input), dictionary.as_deref()));
+    let info = match result {
+        Ok(info) => info,
+        Err(err) => return report(path, &err),
+    };
+    if arg_matches.is_present("json") {
+        println!("{}", info.to_json());
+        return EXIT_SUCCESS;
+    }
+
+    let config = &info.config;
+    println!(
+        "History:             {} bits ({} bytes)",
+        config.history_addr_nbits(),
+        config.history_size()
+    );
+    println!(
+        "Match length:        {} bits ({} .. {} bytes)",
+        config.match_length_nbits(),
+        config.threshold(),
+        config.threshold() + (1 << config.match_length_nbits()) - 1
+    );
+    println!("Threshold:           {}", config.threshold());
+    match config.layout() {
+        Layout::BitPacked => println!("Layout:              bit-packed"),
+        Layout::FlagGroups(flag_count) => {
+            println!("Layout:              flag groups of {}", flag_count)
+        }
+    }
+    if let Some(id) = info.dictionary_id {
+        println!("Dictionary:          {:08x}", id);
+    }
+    println!("Compressed size:     {}", info.compressed_size);
+    println!("Uncompressed size:   {}", info.uncompressed_size);
+    println!("Ratio:               {:.2}%", info.ratio() * 100.0);
+    println!("Literals:            {}", info.literals);
+    println!("References:          {}", info.references);
+    println!("Average match:       {:.2}", info.average_match_length());
+    println!("Distances:");
+    for (bucket, count) in info.distance_histogram.iter().enumerate() {
+        println!(
+            "  {:>6} .. {:<6} {}",
+            1u64 << bucket,
+            (2u64 << bucket) - 1,
+            count
+        );
+    }
+    EXIT_SUCCESS
+}
+
+fn dump_records(arg_matches: &clap::ArgMatches) -> i32 {
+    let dictionary = match read_dictionary(arg_matches) {
+        Ok(dictionary) => dictionary,
+        Err(status) => return status,
+    };
+    let path = arg_matches.value_of("ARCHIVE").unwrap();
+    let format = if arg_matches.is_present("json") {
+        DumpFormat::Json
+    } else {
+        DumpFormat::Text
+    };
+    let result = open_input(Some(path)).and_then(|input| {
+        let mut output = BufWriter::new(io::stdout());
+        dump::dump(
+            BufReader::new(input),
+            dictionary.as_deref(),
+            &mut output,
+            format,
+        )
+    });
+    match result {
+        // Output piped to a program which doesn't read all of it, like head
+        Err(ref err) if err.kind() == io::ErrorKind::BrokenPipe => EXIT_SUCCESS,
+        Err(err) => report(path, &err),
+        Ok(()) => EXIT_SUCCESS,
+    }
+}
+
+fn list_archive(arg_matches: &clap::ArgMatches) -> i32 {
+    let path = arg_matches.value_of("ARCHIVE").unwrap();
+    let result = open_input(Some(path))
+        .and_then(|input| ArchiveReader::new(BufReader::new(input)))
+        .and_then(|mut archive| {
+            while let Some(entry) = archive.next_entry()? {
+                println!(
+                    "{:04o} {:>12} {:>12} {} {}",
+                    entry.mode,
+                    entry.size,
+                    entry.compressed_size,
+                    format_time(entry.mtime),
+                    entry.path
+                );
+            }
+            Ok(())
+        });
+    match result {
+        Ok(()) => EXIT_SUCCESS,
+        Err(err) => report(path, &err),
+    }
+}
+
+// Extracts entries of an archive. Entries which fail are reported and the rest is still
+// extracted, unless archive itself can't be read any further.
+fn extract_archive(arg_matches: &clap::ArgMatches) -> i32 {
+    let path = arg_matches.value_of("ARCHIVE").unwrap();
+    let directory = Path::new(arg_matches.value_of("directory").unwrap());
+    let wanted: Vec<&str> = arg_matches.values_of("PATHS").map_or(Vec::new(), |paths| {
+        paths.map(|path| path.trim_end_matches('/')).collect()
+    });
+    let overwrite = arg_matches.is_present("overwrite");
+
+    let mut status = EXIT_SUCCESS;
+    let result = open_input(Some(path))
+        .and_then(|input| ArchiveReader::new(BufReader::new(input)))
+        .and_then(|mut archive| {
+            while let Some(entry) = archive.next_entry()? {
+                let selected = wanted.is_empty()
+                    || wanted.iter().any(|&wanted| {
+                        entry.path == wanted || entry.path.starts_with(&format!("{}/", wanted))
+                    });
+                if !selected {
+                    continue;
+                }
+                // Valid UTF-8, since both parts are
+                let dest = directory.join(&entry.path).to_string_lossy().into_owned();
+                if let Err(err) = extract_entry(&mut archive, &entry, &dest, overwrite) {
+                    status = status.max(report(&entry.path, &err));
+                }
+            }
+            Ok(())
+        });
+    if let Err(err) = result {
+        status = status.max(report(path, &err));
+    }
+    status
+}
+
+fn extract_entry<R: Read>(
+    archive: &mut ArchiveReader<R>,
+    entry: &Entry,
+    dest: &str,
+    overwrite: bool,
+) -> io::Result<()> {
+    if let Some(parent) = Path::new(dest).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_output(Some(dest), overwrite, |output| {
+        archive.read_data(entry, output)
+    })?;
+    let mtime = UNIX_EPOCH + Duration::from_secs(entry.mtime);
+    let file = OpenOptions::new().write(true).open(dest)?;
+    file.set_times(FileTimes::new().set_accessed(mtime).set_modified(mtime))?;
+    // Like tar without -p, setuid, setgid and sticky bits are not restored
+    set_file_mode(Path::new(dest), entry.mode & 0o777)
+}
+
+// Seconds since Unix epoch as UTC date and time
+fn format_time(secs: u64) -> String {
+    // Days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    let secs_of_day = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60
+    )
+}
+
+// Standard input if `path` is None or -
+fn open_input(path: Option<&str>) -> io::Result<Box<dyn Read>> {
+    match path {
+        None | Some("-") => Ok(Box::new(io::stdin())),
+        Some(path) => Ok(Box::new(File::open(path)?)),
+    }
+}
+
+// Error of reading from one file and writing to another, telling which one it concerns
+enum FileError {
+    Source(io::Error),
+    Dest(io::Error),
+}
+
+impl FileError {
+    // Prints the error against the file it concerns and returns it's exit code
+    fn report(&self, source: &str, dest: &str) -> i32 {
+        match self {
+            FileError::Source(err) => report(source, err),
+            FileError::Dest(err) => report(dest, err),
+        }
+    }
+}
+
+impl From<FileError> for io::Error {
+    fn from(err: FileError) -> io::Error {
+        match err {
+            FileError::Source(err) | FileError::Dest(err) => err,
+        }
+    }
+}
+
+// Remembers whether writing failed, so that errors of writing can be told apart from other
+// errors of whatever writes through it
+struct TrackedWriter<W: Write> {
+    inner: W,
+    failed: bool,
+}
+
+impl<W: Write> TrackedWriter<W> {
+    fn track<T>(&mut self, result: io::Result<T>) -> io::Result<T> {
+        if result
+            .as_ref()
+            .is_err_and(|err| err.kind() != io::ErrorKind::Interrupted)
+        {
+            self.failed = true;
+        }
+        result
+    }
+}
+
+impl<W: Write> Write for TrackedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.inner.write(buf);
+        self.track(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = self.inner.flush();
+        self.track(result)
+    }
+}
+
+// Fills `writer` with `write` and flushes it. Errors of writing are errors of the destination,
+// others (such as errors of reading the input) are errors of the source.
+fn write_to<W, F>(writer: W, write: F) -> Result<W, FileError>
+where
+    W: Write,
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+{
+    let mut output = TrackedWriter {
+        inner: writer,
+        failed: false,
+    };
+    match write(&mut output).and_then(|()| output.flush()) {
+        Ok(()) => Ok(output.inner),
+        Err(err) if output.failed => Err(FileError::Dest(err)),
+        Err(err) => Err(FileError::Source(err)),
+    }
+}
+
+// Creates the output and fills it with `write`. Standard output is used if `path` is None.
+// Files are written to a temporary file in the same directory, which replaces the destination only
+// after all of it is written and synced, so a failed or interrupted run never leaves a truncated
+// file behind (or destroys the one being overwritten). Devices and other special files are
+// written directly.
+fn write_output<F>(path: Option<&str>, overwrite: bool, write: F) -> Result<(), FileError>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+{
+    let path = match path {
+        Some(path) => Path::new(path),
+        None => return write_to(BufWriter::new(io::stdout()), write).map(|_| ()),
+    };
+    match fs::metadata(path) {
+        Ok(_) if !overwrite => {
+            return Err(FileError::Dest(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "File exists",
+            )))
+        }
+        Ok(metadata) if !metadata.is_file() => {
+            let file = OpenOptions::new()
+                .write(true)
+                .open(path)
+                .map_err(FileError::Dest)?;
+            return write_to(BufWriter::new(file), write).map(|_| ());
+        }
+        _ => {}
+    }
+
+    // Symbolic links are kept, the file they point to is replaced
+    let dest = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let (temp_path, file) = create_temp_file(&dest).map_err(FileError::Dest)?;
+    // Replaced file keeps it's owner and permissions
+    if let Ok(existing) = fs::metadata(&dest) {
+        copy_owner(&file, &existing);
+        if let Err(err) = file.set_permissions(existing.permissions()) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(FileError::Dest(err));
+        }
+    }
+    let result = write_to(BufWriter::new(file), write).and_then(|output| {
+        output
+            .into_inner()
+            .map_err(|err| err.into_error())
+            .and_then(|file| file.sync_all())
+            .and_then(|()| move_into_place(&temp_path, &dest, overwrite))
+            .map_err(FileError::Dest)
+    });
+    if result.is_err() {
+        // Error of writing is more interesting than this one
+        let _ = fs::remove_file(&temp_path);
+        return result;
+    }
+    sync_parent_dir(&dest);
+    Ok(())
+}
+
+// Moves finished temporary file to `dest`. Unless overwriting, a file which was created there
+// while the output was being written is not replaced.
+fn move_into_place(temp_path: &Path, dest: &Path, overwrite: bool) -> io::Result<()> {
+    if overwrite {
+        return fs::rename(temp_path, dest);
+    }
+    let exists = || io::Error::new(io::ErrorKind::AlreadyExists, "File exists");
+    match fs::hard_link(temp_path, dest) {
+        Ok(()) => {
+            // Output is in place already
+            let _ = fs::remove_file(temp_path);
+            Ok(())
+        }
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Err(exists()),
+        // File systems without hard links: the name is taken with an empty file first
+        Err(_) => {
             OpenOptions::new()
                 .write(true)
                 .create_new(true)
-                .open(filepath)
-                .unwrap()
-        };
-        let mut buff_writer = BufWriter::new(dest_file);
+                .open(dest)
+                .map_err(|err| match err.kind() {
+                    io::ErrorKind::AlreadyExists => exists(),
+                    _ => err,
+                })?;
+            fs::rename(temp_path, dest).inspect_err(|_| {
+                let _ = fs::remove_file(dest);
+            })
+        }
+    }
+}
 
-        let res = decoder::decode(&mut buff_reader, &mut buff_writer);
-        if res.is_err() {
-            panic!("Error decoding: {}", res.err().unwrap().to_string());
+// Hidden file next to `dest`, named after it
+fn create_temp_file(dest: &Path) -> io::Result<(PathBuf, File)> {
+    let name = dest.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Output path is not a file name",
+        )
+    })?;
+    for attempt in 0.. {
+        let temp_path = dest.with_file_name(format!(
+            ".{}.{}-{}.tmp",
+            name.to_string_lossy(),
+            process::id(),
+            attempt
+        ));
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+        {
+            Ok(file) => return Ok((temp_path, file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
         }
-    } else {
-        panic!("Unknown command");
+    }
+    unreachable!("Temporary file names ran out")
+}
+
+// Makes the rename durable. Not every file system supports syncing directories, so errors are
+// ignored.
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) {
+    if let Some(dir) = path.parent() {
+        let _ = File::open(dir).and_then(|dir| dir.sync_all());
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) {}
+
+// Gives `file` owner and group of `existing` file. Only root can give files away, so errors
+// are ignored and at least the group is kept if possible.
+#[cfg(unix)]
+fn copy_owner(file: &File, existing: &fs::Metadata) {
+    use std::os::unix::fs::{fchown, MetadataExt};
+    if fchown(file, Some(existing.uid()), Some(existing.gid())).is_err() {
+        let _ = fchown(file, None, Some(existing.gid()));
+    }
+}
+
+#[cfg(not(unix))]
+fn copy_owner(_file: &File, _existing: &fs::Metadata) {}
+
+fn is_flag_count(value: String) -> Result<(), String> {
+    match value.as_str() {
+        "8" | "16" | "32" => Ok(()),
+        _ => Err(String::from("Flag group size has to be 8, 16 or 32")),
     }
 }
 
-// fn construct_archive_path(filepath: &str) -> String {
-//     format!("{}.lzss", filepath)
-// }
+fn is_history_size(value: String) -> Result<(), String> {
+    is_in_range(&value, MIN_HISTORY_ADDR_BITS, MAX_HISTORY_ADDR_BITS)
+}
+
+fn is_match_length_size(value: String) -> Result<(), String> {
+    is_in_range(&value, MIN_MATCH_LENGTH_BITS, MAX_MATCH_LENGTH_BITS)
+}
+
+fn is_search_depth(value: String) -> Result<(), String> {
+    is_in_range(&value, 0, usize::from(u8::MAX))
+}
+
+fn is_size(value: String) -> Result<(), String> {
+    is_in_range(&value, 1, usize::MAX)
+}
+
+fn is_in_range(value: &str, min: usize, max: usize) -> Result<(), String> {
+    match value.parse() {
+        Ok(number) if (min..=max).contains(&number) => Ok(()),
+        _ => Err(format!("Has to be a number from {} to {}", min, max)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn derived_paths() {
+        assert_eq!(archive_path("a.c", ".lzss", false).unwrap(), "a.c.lzss");
+        assert!(archive_path("a.lzss", ".lzss", false).is_err());
+        assert_eq!(
+            archive_path("a.lzss", ".lzss", true).unwrap(),
+            "a.lzss.lzss"
+        );
+        assert!(archive_path("a.c", "", true).is_err());
+
+        assert_eq!(original_path("a.c.lzss", ".lzss").unwrap(), "a.c");
+        assert!(original_path("a.c", ".lzss").is_err());
+        assert!(original_path(".lzss", ".lzss").is_err());
+        assert!(original_path("a.c", "").is_err());
+    }
+
+    #[test]
+    fn exit_codes() {
+        let error = |kind| io::Error::new(kind, "error");
+        assert_eq!(exit_code(&error(io::ErrorKind::NotFound)), EXIT_IO_ERROR);
+        assert_eq!(exit_code(&error(io::ErrorKind::InvalidInput)), EXIT_USAGE);
+        assert_eq!(
+            exit_code(&error(io::ErrorKind::InvalidData)),
+            EXIT_CORRUPT_DATA
+        );
+        assert_eq!(
+            exit_code(&error(io::ErrorKind::UnexpectedEof)),
+            EXIT_CORRUPT_DATA
+        );
+        assert_eq!(
+            exit_code(&checksum_mismatch("Checksum mismatch")),
+            EXIT_CHECKSUM_MISMATCH
+        );
+    }
+
+    // Fails every write
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("No space left on device"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn errors_are_told_apart() {
+        let result = write_to(FullDisk, |output| output.write_all(b"data"));
+        assert!(matches!(result, Err(FileError::Dest(_))));
+
+        let result = write_to(Vec::new(), |output| {
+            output.write_all(b"data")?;
+            Err(io::Error::new(io::ErrorKind::InvalidData, "corrupt"))
+        });
+        assert!(matches!(result, Err(FileError::Source(_))));
+
+        assert_eq!(
+            write_to(Vec::new(), |output| output.write_all(b"data")).ok(),
+            Some(b"data".to_vec())
+        );
+    }
+
+    // Empty directory for files of a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("lzss-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn file_created_while_writing_is_kept() {
+        let dir = test_dir("created-while-writing");
+        let dest = dir.join("out");
+        let result = write_output(dest.to_str(), false, |output| {
+            fs::write(&dest, b"theirs")?;
+            output.write_all(b"ours")
+        });
+        assert!(matches!(result, Err(FileError::Dest(ref err))
+            if err.kind() == io::ErrorKind::AlreadyExists));
+        assert_eq!(fs::read(&dest).unwrap(), b"theirs");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_write_keeps_destination() {
+        let dir = test_dir("failed-write");
+        let dest = dir.join("out");
+        fs::write(&dest, b"old").unwrap();
+        let result = write_output(dest.to_str(), true, |output| {
+            output.write_all(b"partial")?;
+            Err(io::Error::new(io::ErrorKind::InvalidData, "corrupt"))
+        });
+        assert!(matches!(result, Err(FileError::Source(_))));
+        assert_eq!(fs::read(&dest).unwrap(), b"old");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let result = write_output(dest.to_str(), false, |output| output.write_all(b"new"));
+        assert!(matches!(result, Err(FileError::Dest(_))));
+        assert_eq!(fs::read(&dest).unwrap(), b"old");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replaced_file_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = test_dir("permissions");
+        let dest = dir.join("out");
+        fs::write(&dest, b"old").unwrap();
+        fs::set_permissions(&dest, fs::Permissions::from_mode(0o600)).unwrap();
+        write_output(dest.to_str(), true, |output| output.write_all(b"new"))
+            .map_err(io::Error::from)
+            .unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"new");
+        let mode = fs::metadata(&dest).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn extracted_modes_drop_special_bits() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = test_dir("extract-modes");
+        let dest = dir.join("out");
+        let mut writer = ArchiveWriter::new(Vec::new(), Level::L1).unwrap();
+        writer.add("out", 0o4755, 0, &mut &b"data"[..]).unwrap();
+        let data = writer.finish().unwrap();
+        let mut archive = ArchiveReader::new(data.as_slice()).unwrap();
+        let entry = archive.next_entry().unwrap().unwrap();
+        extract_entry(&mut archive, &entry, dest.to_str().unwrap(), false).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"data");
+        let mode = fs::metadata(&dest).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o755);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gzip_style_command_line() {
+        assert_eq!(
+            gzip_style_args(args(&["lzss", "-dk", "a.lzss", "-"])),
+            args(&[
+                "lzss",
+                "decode",
+                "--gzip-style",
+                "--multiple",
+                "-k",
+                "a.lzss",
+                "-"
+            ])
+        );
+        assert_eq!(
+            gzip_style_args(args(&["lzss", "--decompress", "-S", "-d", "a-d"])),
+            args(&[
+                "lzss",
+                "decode",
+                "--gzip-style",
+                "--multiple",
+                "-S",
+                "-d",
+                "a-d"
+            ])
+        );
+        assert_eq!(
+            gzip_style_args(args(&["lzss", "-9kS.z", "--suffix", "-k", "--", "-dk"])),
+            args(&[
+                "lzss",
+                "encode",
+                "--gzip-style",
+                "--multiple",
+                "-9",
+                "-k",
+                "-S",
+                ".z",
+                "--suffix",
+                "-k",
+                "--",
+                "-dk"
+            ])
+        );
+        assert_eq!(
+            gzip_style_args(args(&["lzss", "-ck", "a"])),
+            args(&[
+                "lzss",
+                "encode",
+                "--gzip-style",
+                "--multiple",
+                "--stdout",
+                "-k",
+                "a"
+            ])
+        );
+        assert_eq!(
+            gzip_style_args(args(&["lzss", "encode", "-d", "1", "a"])),
+            args(&["lzss", "encode", "-d", "1", "a"])
+        );
+    }
+}
@@ -0,0 +1,287 @@
+// Strategies for finding matches while encoding.
+// Encoder drives a finder through the whole stream: bytes which enter history without a search
+// are passed to `feed`, then for every token `find_match` is asked about the beginning of the
+// current window and `skip` is told how many bytes the chosen token covered.
+// Stateful finders (hash chains, trees) can keep their index up to date from these calls.
+// `reset` is called before every stream, so one finder can be used for several of them.
+use super::search;
+
+pub trait MatchFinder {
+    // Forgets everything seen so far, a new stream starts
+    fn reset(&mut self) {}
+
+    // Bytes appended to history without searching (e.g. unencoded beginning of a stream)
+    fn feed(&mut self, _bytes: &[u8]) {}
+
+    // Finds the best match for the beginning of `window` in `history`.
+    // Returns it's position in history and length. Length 0 means no match of at least
+    // `threshold` bytes was found.
+    fn find_match(&mut self, history: &[u8], window: &[u8], threshold: usize) -> (usize, usize);
+
+    // First `n` bytes of `window` were encoded and are moving to history
+    fn skip(&mut self, _history: &[u8], _window: &[u8], _n: usize) {}
+}
+
+// Linear search through the whole history (see `search::best_match`)
+#[derive(Debug, Clone, Copy)]
+pub struct LinearSearch {
+    search_depth: usize, // 0 - longest match, 1 - first match, 2 - longest of the first two matches
+}
+
+impl LinearSearch {
+    pub fn new(search_depth: usize) -> LinearSearch {
+        LinearSearch { search_depth }
+    }
+}
+
+impl MatchFinder for LinearSearch {
+    fn find_match(&mut self, history: &[u8], window: &[u8], threshold: usize) -> (usize, usize) {
+        search::best_match(history, window, threshold, self.search_depth)
+    }
+}
+
+// Number of bytes a hash is computed from. Shorter matches are not found.
+const HASH_LEN: usize = 3;
+const HASH_BITS: u32 = 16;
+// Marks an empty slot in `HashChain::head`
+const NO_POSITION: u32 = u32::MAX;
+
+// Finds matches by following chains of earlier positions with the same hash of their first
+// bytes, like zlib does. Much faster than a linear search for big histories.
+// With lazy matching a match is dropped if the next position has a longer one, so a literal is
+// emitted and the longer match is found by the next search.
+// Positions are stored as stream offsets truncated to 32 bits and only ever compared by their
+// distance from the end of history, which is always smaller than 2^32.
+pub struct HashChain {
+    history_size: usize,
+    max_chain: usize, // How many candidates are checked for a single match
+    lazy: bool,
+    // Most recent position for every hash
+    head: Vec<u32>,
+    // Previous position with the same hash, indexed by position modulo history size
+    prev: Vec<u32>,
+    mask: usize,
+    // Stream offset of the first byte of current window
+    total: usize,
+    // Positions before this one were added to chains
+    inserted: usize,
+}
+
+impl HashChain {
+    pub fn new(history_size: usize, max_chain: usize) -> HashChain {
+        let ring_size = history_size.next_power_of_two();
+        HashChain {
+            history_size,
+            max_chain,
+            lazy: false,
+            head: vec![NO_POSITION; 1 << HASH_BITS],
+            prev: vec![NO_POSITION; ring_size],
+            mask: ring_size - 1,
+            total: 0,
+            inserted: 0,
+        }
+    }
+
+    pub fn with_lazy_matching(mut self) -> HashChain {
+        self.lazy = true;
+        self
+    }
+
+    fn hash(bytes: [u8; HASH_LEN]) -> usize {
+        let key = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+        (key.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+    }
+
+    // Adds positions of history which were not added yet. Their first bytes may be in window.
+    fn insert_history(&mut self, history: &[u8], window: &[u8]) {
+        let history_start = self.total - history.len();
+        self.inserted = usize::max(self.inserted, history_start);
+        let byte_at = |offset: usize| {
+            let index = offset - history_start;
+            if index < history.len() {
+                Some(history[index])
+            } else {
+                window.get(index - history.len()).copied()
+            }
+        };
+        while self.inserted < self.total {
+            let pos = self.inserted;
+            let bytes = match (byte_at(pos), byte_at(pos + 1), byte_at(pos + 2)) {
+                (Some(b0), Some(b1), Some(b2)) => [b0, b1, b2],
+                // End of input, these positions can't start a match anyway
+                _ => break,
+            };
+            self.insert(pos, bytes);
+        }
+    }
+
+    fn insert(&mut self, pos: usize, bytes: [u8; HASH_LEN]) {
+        let hash = HashChain::hash(bytes);
+        self.prev[pos & self.mask] = self.head[hash];
+        self.head[hash] = pos as u32;
+        self.inserted = pos + 1;
+    }
+
+    // Finds the longest match for `window[offset..]` in history followed by `window[..offset]`.
+    // Returns it's distance back from `window[offset]` and length.
+    fn longest_match(&self, history: &[u8], window: &[u8], offset: usize) -> (usize, usize) {
+        let current = &window[offset..];
+        if current.len() < HASH_LEN {
+            return (0, 0);
+        }
+        let end = (self.total + offset) as u32;
+        // Bytes which are still going to be in history when `window[offset]` is encoded
+        let available = usize::min(history.len() + offset, self.history_size);
+        let mut candidate = self.head[HashChain::hash([current[0], current[1], current[2]])];
+        let mut last_distance = 0;
+        let mut best = (0, 0);
+        for _ in 0..self.max_chain {
+            if candidate == NO_POSITION {
+                break;
+            }
+            let distance = end.wrapping_sub(candidate) as usize;
+            // Chains may lead to positions which already left history or to stale ring entries
+            if distance <= last_distance || distance > available {
+                break;
+            }
+            last_distance = distance;
+
+            let start = history.len() + offset - distance;
+            let earlier = history[usize::min(start, history.len())..]
+                .iter()
+                .chain(&window[start.saturating_sub(history.len())..offset]);
+            // Matches don't run past `window[offset]`
+            let length = earlier.zip(current).take_while(|(a, b)| a == b).count();
+            if length > best.1 {
+                best = (distance, length);
+                if length == current.len() {
+                    break;
+                }
+            }
+            candidate = self.prev[candidate as usize & self.mask];
+        }
+        best
+    }
+}
+
+impl MatchFinder for HashChain {
+    fn reset(&mut self) {
+        self.head.fill(NO_POSITION);
+        self.prev.fill(NO_POSITION);
+        self.total = 0;
+        self.inserted = 0;
+    }
+
+    fn feed(&mut self, bytes: &[u8]) {
+        self.total += bytes.len();
+    }
+
+    fn find_match(&mut self, history: &[u8], window: &[u8], threshold: usize) -> (usize, usize) {
+        self.insert_history(history, window);
+        if window.len() < usize::max(threshold, HASH_LEN) {
+            return (0, 0);
+        }
+
+        let (distance, length) = self.longest_match(history, window, 0);
+        if length < threshold {
+            return (0, 0);
+        }
+        if self.lazy && length < window.len() {
+            if self.inserted == self.total {
+                self.insert(self.total, [window[0], window[1], window[2]]);
+            }
+            if self.longest_match(history, window, 1).1 > length {
+                return (0, 0);
+            }
+        }
+        (history.len() - distance, length)
+    }
+
+    fn skip(&mut self, _history: &[u8], _window: &[u8], n: usize) {
+        self.total += n;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encoder::Encoder;
+    use crate::{decoder, tokenize_with, Config, Token};
+
+    #[test]
+    fn hash_chain_round_trip() {
+        let data = std::fs::read("test-files/alice29.txt").unwrap();
+        let data = &data[..50_000];
+        let config = Config::new(12, 4, 0);
+
+        let mut linear = Vec::new();
+        Encoder::with_config(config)
+            .encode(&mut &data[..], &mut linear)
+            .unwrap();
+        let mut archive = Vec::new();
+        let finder = HashChain::new(config.history_size(), 4096);
+        Encoder::with_match_finder(config, Box::new(finder))
+            .encode(&mut &data[..], &mut archive)
+            .unwrap();
+        // Linear search only looks for the rest of a partial match after it
+        assert!(archive.len() <= linear.len());
+
+        let mut decoded = Vec::new();
+        decoder::decode(archive.as_slice(), &mut decoded).unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    // Only finds repeats of the last byte, and only after `reset`
+    struct RunFinder {
+        ready: bool,
+    }
+
+    impl MatchFinder for RunFinder {
+        fn reset(&mut self) {
+            self.ready = true;
+        }
+
+        fn find_match(
+            &mut self,
+            history: &[u8],
+            window: &[u8],
+            threshold: usize,
+        ) -> (usize, usize) {
+            assert!(self.ready);
+            let last = match history.last() {
+                Some(&last) => last,
+                None => return (0, 0),
+            };
+            let length = window.iter().take_while(|&&byte| byte == last).count();
+            if length < threshold {
+                return (0, 0);
+            }
+            (history.len() - 1, length)
+        }
+    }
+
+    #[test]
+    fn user_defined_finder() {
+        let data = b"abcccccccccccccccccccccdeeeeeeeeeeeeef";
+        let config = Config::new(8, 4, 0);
+        let mut encoder = Encoder::with_match_finder(config, Box::new(RunFinder { ready: false }));
+        let mut first = Vec::new();
+        encoder.encode(&mut &data[..], &mut first).unwrap();
+        let mut second = Vec::new();
+        encoder.encode(&mut &data[..], &mut second).unwrap();
+        assert_eq!(first, second);
+
+        let tokens: Vec<Token> =
+            tokenize_with(data, &config, Box::new(RunFinder { ready: false })).collect();
+        assert!(tokens.len() < data.len());
+        let mut reencoded = Vec::new();
+        Encoder::with_config(config)
+            .encode_tokens(tokens, &mut reencoded)
+            .unwrap();
+        assert_eq!(reencoded, first);
+
+        let mut decoded = Vec::new();
+        decoder::decode(first.as_slice(), &mut decoded).unwrap();
+        assert_eq!(decoded, data);
+    }
+}
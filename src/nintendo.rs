@@ -0,0 +1,371 @@
+// LZ77 formats of the GBA/DS BIOS decompression functions (LZ77UnComp, type 0x10) and its
+// extension LZ11 (type 0x11) used by DS games.
+// A 4 byte header holds the type and the decompressed size (24 bits, little endian). If the size
+// is 0, the real size follows in the next 4 bytes. Flags of 8 records are stored in one byte,
+// most significant bit first (1 - reference, 0 - literal). A reference stores distance - 1 in 12
+// bits and the length:
+//   LZ10: 2 bytes, length - 3 in the upper 4 bits of the first byte (3-18)
+//   LZ11: upper 4 bits of the first byte select the form
+//     0 - 3 bytes, 8 bit length - 17 (17-272)
+//     1 - 4 bytes, 16 bit length - 273 (273-65808)
+//     n - 2 bytes, length - 1 is n (3-16)
+// Stream ends once decompressed size is reached. Compressed data is padded to a multiple of 4 bytes.
+use super::bit_io::{BitReader, BitWriter};
+use super::codec::{BitOrder, FlagGroupReader, FlagGroupWriter, RecordCodec};
+use super::decoder::Decoder;
+use super::encoder::Encoder;
+use super::match_finder::LinearSearch;
+use super::Token;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+// Maximum distance of a reference
+pub const HISTORY_SIZE: usize = 4096;
+pub const MIN_MATCH_LENGTH: usize = 3;
+// Largest size which fits in the short header
+const MAX_SHORT_SIZE: usize = 0xff_ffff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    Lz10,
+    Lz11,
+}
+
+impl Variant {
+    pub fn type_byte(self) -> u8 {
+        match self {
+            Variant::Lz10 => 0x10,
+            Variant::Lz11 => 0x11,
+        }
+    }
+
+    pub fn max_match_length(self) -> usize {
+        match self {
+            Variant::Lz10 => 0x12,
+            Variant::Lz11 => 0x1_0110,
+        }
+    }
+}
+
+pub struct NintendoCodec {
+    variant: Variant,
+    // Decompressed size stored in the header
+    size: usize,
+    // Matches at distance 1 are not allowed. The BIOS writes VRAM 16 bits at a time, so the
+    // previous byte is not there yet when such a match is copied.
+    vram_safe: bool,
+    flags_out: FlagGroupWriter,
+    flags_in: FlagGroupReader,
+    // Number of bytes tokens written or read so far expand to
+    bytes_processed: usize,
+    bytes_written: usize,
+    records_written: usize,
+}
+
+impl NintendoCodec {
+    // Codec for encoding `size` bytes
+    pub fn new(variant: Variant, size: usize, vram_safe: bool) -> NintendoCodec {
+        assert!(size <= u32::MAX as usize, "Size has to fit in 32 bits");
+        NintendoCodec {
+            variant,
+            size,
+            vram_safe,
+            flags_out: FlagGroupWriter::new(8, BitOrder::MsbFirst),
+            flags_in: FlagGroupReader::new(8, BitOrder::MsbFirst),
+            bytes_processed: 0,
+            bytes_written: 0,
+            records_written: 0,
+        }
+    }
+
+    // Reads the header and returns a codec for decoding the rest of the stream
+    pub fn read_header<R: Read>(reader: &mut R) -> Result<NintendoCodec> {
+        let mut header = [0u8; 4];
+        reader.read_exact(&mut header)?;
+        let variant = match header[0] {
+            0x10 => Variant::Lz10,
+            0x11 => Variant::Lz11,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Invalid header: unknown compression type",
+                ))
+            }
+        };
+        let mut size = u32::from_le_bytes([header[1], header[2], header[3], 0]);
+        if size == 0 {
+            reader.read_exact(&mut header)?;
+            size = u32::from_le_bytes(header);
+        }
+        Ok(NintendoCodec::new(variant, size as usize, false))
+    }
+
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    // Decompressed size
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn history_len(&self) -> usize {
+        usize::min(self.bytes_processed, HISTORY_SIZE)
+    }
+
+    fn push_record<W: Write>(
+        &mut self,
+        bw: &mut BitWriter<W>,
+        flag: bool,
+        record: &[u8],
+    ) -> Result<()> {
+        if self.records_written.is_multiple_of(8) {
+            self.bytes_written += 1;
+        }
+        self.records_written += 1;
+        self.bytes_written += record.len();
+        self.flags_out.push(bw, flag, record)
+    }
+
+    fn encode_reference(&self, distance: usize, length: usize) -> ([u8; 4], usize) {
+        let d = distance - 1;
+        match self.variant {
+            Variant::Lz10 => ([(((length - 3) << 4) | (d >> 8)) as u8, d as u8, 0, 0], 2),
+            Variant::Lz11 if length <= 0x10 => {
+                ([(((length - 1) << 4) | (d >> 8)) as u8, d as u8, 0, 0], 2)
+            }
+            Variant::Lz11 if length <= 0x110 => {
+                let l = length - 0x11;
+                ([(l >> 4) as u8, ((l << 4) | (d >> 8)) as u8, d as u8, 0], 3)
+            }
+            Variant::Lz11 => {
+                let l = length - 0x111;
+                (
+                    [
+                        (0x10 | (l >> 12)) as u8,
+                        (l >> 4) as u8,
+                        ((l << 4) | (d >> 8)) as u8,
+                        d as u8,
+                    ],
+                    4,
+                )
+            }
+        }
+    }
+
+    fn read_reference<R: Read>(&mut self, br: &mut BitReader<R>) -> Result<Token> {
+        let b0 = br.read_byte()? as usize;
+        let (length, high) = match self.variant {
+            Variant::Lz10 => ((b0 >> 4) + 3, b0),
+            Variant::Lz11 => match b0 >> 4 {
+                0 => {
+                    let b1 = br.read_byte()? as usize;
+                    ((((b0 & 0x0f) << 4) | (b1 >> 4)) + 0x11, b1)
+                }
+                1 => {
+                    let b1 = br.read_byte()? as usize;
+                    let b2 = br.read_byte()? as usize;
+                    ((((b0 & 0x0f) << 12) | (b1 << 4) | (b2 >> 4)) + 0x111, b2)
+                }
+                n => (n + 1, b0),
+            },
+        };
+        let distance = (((high & 0x0f) << 8) | br.read_byte()? as usize) + 1;
+        if distance > self.history_len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Invalid record: distance points before the beginning of a stream",
+            ));
+        } else if self.bytes_processed + length > self.size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Invalid record: match goes past the decompressed size",
+            ));
+        }
+        Ok(Token::Match {
+            position: self.history_len() - distance,
+            length,
+        })
+    }
+}
+
+impl RecordCodec for NintendoCodec {
+    fn history_size(&self) -> usize {
+        HISTORY_SIZE
+    }
+
+    fn min_match_length(&self) -> usize {
+        MIN_MATCH_LENGTH
+    }
+
+    fn max_match_length(&self) -> usize {
+        self.variant.max_match_length()
+    }
+
+    fn write_header<W: Write>(&mut self, bw: &mut BitWriter<W>) -> Result<()> {
+        let size = (self.size as u32).to_le_bytes();
+        if self.size <= MAX_SHORT_SIZE && self.size > 0 {
+            bw.write_bytes(&[self.variant.type_byte(), size[0], size[1], size[2]])?;
+            self.bytes_written = 4;
+        } else {
+            bw.write_bytes(&[self.variant.type_byte(), 0, 0, 0])?;
+            bw.write_bytes(&size)?;
+            self.bytes_written = 8;
+        }
+        Ok(())
+    }
+
+    fn write_token<W: Write>(&mut self, bw: &mut BitWriter<W>, token: Token) -> Result<()> {
+        match token {
+            Token::Literal(byte) => self.push_record(bw, false, &[byte])?,
+            Token::Match { position, length } => {
+                let distance = self.history_len() - position;
+                if self.vram_safe && distance == 1 {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "Matches at distance 1 are not VRAM safe",
+                    ));
+                }
+                let (record, len) = self.encode_reference(distance, length);
+                self.push_record(bw, true, &record[..len])?;
+            }
+        }
+        self.bytes_processed += token.decoded_len();
+        Ok(())
+    }
+
+    fn finish<W: Write>(&mut self, bw: &mut BitWriter<W>) -> Result<()> {
+        if self.bytes_processed != self.size {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Encoded data does not match the size in the header",
+            ));
+        }
+        self.flags_out.flush(bw)?;
+        let padding = (4 - self.bytes_written % 4) % 4;
+        bw.write_bytes(&[0; 3][..padding])
+    }
+
+    fn read_token<R: Read>(&mut self, br: &mut BitReader<R>) -> Result<Option<Token>> {
+        if self.bytes_processed == self.size {
+            // Whatever follows is padding
+            return Ok(None);
+        }
+        let token = match self.flags_in.next_flag(br)? {
+            None => {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Stream ended before the decompressed size was reached",
+                ))
+            }
+            Some(false) => Token::Literal(br.read_byte()?),
+            Some(true) => self.read_reference(br)?,
+        };
+        self.bytes_processed += token.decoded_len();
+        Ok(Some(token))
+    }
+}
+
+// Whole input is read into memory first, because the header needs it's size
+pub fn encode<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    variant: Variant,
+    vram_safe: bool,
+) -> Result<()> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    if data.len() > u32::MAX as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Input is too large for this format",
+        ));
+    }
+    let codec = NintendoCodec::new(variant, data.len(), vram_safe);
+    let mut encoder = Encoder::with_codec(codec, Box::new(LinearSearch::new(0)));
+    encoder.encode(&mut data.as_slice(), writer)
+}
+
+// Variant is taken from the header
+pub fn decode<R: Read, W: Write>(mut reader: R, writer: W) -> Result<()> {
+    let codec = NintendoCodec::read_header(&mut reader)?;
+    Decoder::with_codec(reader, writer, codec).decode()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decoded(archive: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::new();
+        decode(archive, &mut decoded).unwrap();
+        decoded
+    }
+
+    fn encoded(data: &[u8], variant: Variant) -> Vec<u8> {
+        let mut archive = Vec::new();
+        encode(&mut &data[..], &mut archive, variant, true).unwrap();
+        archive
+    }
+
+    #[test]
+    fn lz10_fixtures() {
+        let archive = [
+            0x10, 0x0c, 0x00, 0x00, 0x18, b'a', b'b', b'c', 0x00, 0x02, 0x30, 0x05,
+        ];
+        assert_eq!(encoded(b"abcabcabcabc", Variant::Lz10), archive);
+        assert_eq!(decoded(&archive), b"abcabcabcabc");
+
+        // Overlapping match at distance 1 (not produced by our encoder) and padding
+        let archive = [0x10, 0x0a, 0x00, 0x00, 0x40, b'a', 0x60, 0x00];
+        assert_eq!(decoded(&archive), b"aaaaaaaaaa");
+    }
+
+    #[test]
+    fn lz11_fixtures() {
+        let archive = [
+            0x11, 0x0c, 0x00, 0x00, 0x18, b'a', b'b', b'c', 0x20, 0x02, 0x50, 0x05,
+        ];
+        assert_eq!(encoded(b"abcabcabcabc", Variant::Lz11), archive);
+        assert_eq!(decoded(&archive), b"abcabcabcabc");
+
+        // 4 byte reference (length 299) and a 3 byte one (length 20)
+        let archive = [
+            0x11, 0x40, 0x01, 0x00, 0x60, b'a', 0x10, 0x01, 0xa0, 0x00, 0x00, 0x30, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+        assert_eq!(decoded(&archive), vec![b'a'; 320]);
+    }
+
+    #[test]
+    fn vram_safe_rejects_distance_1() {
+        let mut archive = Vec::new();
+        let mut encoder = Encoder::with_codec(
+            NintendoCodec::new(Variant::Lz10, 4, true),
+            Box::new(LinearSearch::new(0)),
+        );
+        let tokens = vec![
+            Token::Literal(b'a'),
+            Token::Match {
+                position: 0,
+                length: 3,
+            },
+        ];
+        let err = encoder.encode_tokens(tokens, &mut archive).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut data = std::fs::read("test-files/fields.c").unwrap();
+        // Long repeats for the extended LZ11 lengths
+        let block = data[0..1000].to_vec();
+        for _ in 0..5 {
+            data.extend_from_slice(&block);
+        }
+        for &variant in &[Variant::Lz10, Variant::Lz11] {
+            let archive = encoded(&data, variant);
+            assert_eq!(archive.len() % 4, 0);
+            assert_eq!(decoded(&archive), data);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+// Format of Haruhiko Okumura's LZSS.C (1989).
+// A 4096 byte ring buffer starts with 4078 spaces. Flags of 8 records are stored in one byte,
+// least significant bit first (1 - literal, 0 - reference). A literal is one byte. A reference
+// is two bytes: lower 8 bits of a ring buffer position, then upper 4 bits of the position and
+// match length - 3 in the lower 4 bits. There is no header, stream ends where input ends.
+use super::bit_io::{BitReader, BitWriter};
+use super::codec::{BitOrder, FlagGroupReader, FlagGroupWriter, RecordCodec};
+use super::decoder::Decoder;
+use super::encoder::Encoder;
+use super::match_finder::LinearSearch;
+use super::Token;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+// Size of the ring buffer
+pub const RING_SIZE: usize = 4096;
+// Upper limit for match length
+pub const MAX_MATCH_LENGTH: usize = 18;
+// Matches of this length or shorter are encoded as literals
+pub const THRESHOLD: usize = 2;
+// Position in the ring buffer where the first byte of a stream is stored
+const START_POSITION: usize = RING_SIZE - MAX_MATCH_LENGTH;
+
+pub struct OkumuraCodec {
+    preset: Vec<u8>,
+    flags_out: FlagGroupWriter,
+    flags_in: FlagGroupReader,
+    // Number of bytes tokens written or read so far expand to
+    bytes_processed: usize,
+}
+
+impl OkumuraCodec {
+    pub fn new() -> OkumuraCodec {
+        OkumuraCodec {
+            preset: vec![b' '; START_POSITION],
+            flags_out: FlagGroupWriter::new(8, BitOrder::LsbFirst),
+            flags_in: FlagGroupReader::new(8, BitOrder::LsbFirst),
+            bytes_processed: 0,
+        }
+    }
+
+    // Index in history of the oldest byte, in terms of ring buffer positions
+    fn history_start(&self) -> usize {
+        let total = START_POSITION + self.bytes_processed;
+        total - usize::min(total, RING_SIZE)
+    }
+
+    fn history_len(&self) -> usize {
+        usize::min(START_POSITION + self.bytes_processed, RING_SIZE)
+    }
+
+    fn read_reference<R: Read>(&mut self, br: &mut BitReader<R>) -> Result<Option<Token>> {
+        let (low, high) = match (br.read_byte(), br.read_byte()) {
+            (Ok(low), Ok(high)) => (low as usize, high as usize),
+            // Stream ends in the middle of a reference, just like the original decoder stops
+            (Err(ref error), _) | (_, Err(ref error))
+                if error.kind() == ErrorKind::UnexpectedEof =>
+            {
+                return Ok(None)
+            }
+            (Err(error), _) | (_, Err(error)) => return Err(error),
+        };
+        let ring_position = low | ((high & 0xf0) << 4);
+        let length = (high & 0x0f) + THRESHOLD + 1;
+        let position = (ring_position + RING_SIZE - self.history_start() % RING_SIZE) % RING_SIZE;
+        if position >= self.history_len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Invalid record: position points to a part of ring buffer which was not written yet",
+            ));
+        }
+        Ok(Some(Token::Match { position, length }))
+    }
+}
+
+impl Default for OkumuraCodec {
+    fn default() -> OkumuraCodec {
+        OkumuraCodec::new()
+    }
+}
+
+impl RecordCodec for OkumuraCodec {
+    fn history_size(&self) -> usize {
+        RING_SIZE
+    }
+
+    fn min_match_length(&self) -> usize {
+        THRESHOLD + 1
+    }
+
+    fn max_match_length(&self) -> usize {
+        MAX_MATCH_LENGTH
+    }
+
+    fn preset_history(&self) -> &[u8] {
+        &self.preset
+    }
+
+    fn write_token<W: Write>(&mut self, bw: &mut BitWriter<W>, token: Token) -> Result<()> {
+        match token {
+            Token::Literal(byte) => self.flags_out.push(bw, true, &[byte])?,
+            Token::Match { position, length } => {
+                let ring_position = (self.history_start() + position) % RING_SIZE;
+                let low = ring_position as u8;
+                let high = ((ring_position >> 4) & 0xf0) as u8 | (length - THRESHOLD - 1) as u8;
+                self.flags_out.push(bw, false, &[low, high])?;
+            }
+        }
+        self.bytes_processed += token.decoded_len();
+        Ok(())
+    }
+
+    fn finish<W: Write>(&mut self, bw: &mut BitWriter<W>) -> Result<()> {
+        self.flags_out.flush(bw)
+    }
+
+    fn read_token<R: Read>(&mut self, br: &mut BitReader<R>) -> Result<Option<Token>> {
+        let token = match self.flags_in.next_flag(br)? {
+            None => None,
+            Some(true) => match br.read_byte() {
+                Ok(byte) => Some(Token::Literal(byte)),
+                Err(ref error) if error.kind() == ErrorKind::UnexpectedEof => None,
+                Err(error) => return Err(error),
+            },
+            Some(false) => self.read_reference(br)?,
+        };
+        if let Some(token) = token {
+            self.bytes_processed += token.decoded_len();
+        }
+        Ok(token)
+    }
+}
+
+pub fn encode<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<()> {
+    let mut encoder = Encoder::with_codec(OkumuraCodec::new(), Box::new(LinearSearch::new(0)));
+    encoder.encode(reader, writer)
+}
+
+pub fn decode<R: Read, W: Write>(reader: R, writer: W) -> Result<()> {
+    Decoder::with_codec(reader, writer, OkumuraCodec::new()).decode()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decodes_stream_of_original_implementation() {
+        // Output of LZSS.C for "abcabcabcabc  xyz"
+        let archive = [
+            0xf7, b'a', b'b', b'c', 0xee, 0xf6, b' ', b' ', b'x', b'y', 0x01, b'z',
+        ];
+        let mut decoded = Vec::new();
+        decode(&archive[..], &mut decoded).unwrap();
+        assert_eq!(decoded, b"abcabcabcabc  xyz");
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = std::fs::read("test-files/fields.c").unwrap();
+        let mut archive = Vec::new();
+        encode(&mut data.as_slice(), &mut archive).unwrap();
+        let mut decoded = Vec::new();
+        decode(archive.as_slice(), &mut decoded).unwrap();
+        assert_eq!(decoded, data);
+    }
+}
@@ -0,0 +1,175 @@
+use std::io::{Error, ErrorKind, Result, Write};
+
+// How many decoded bytes are collected before they are written out
+const FLUSH_SIZE: usize = 64 * 1024;
+
+// Bytes past the end of a match which `copy_match_within` may overwrite
+pub const COPY_SLACK: usize = 16;
+
+// Decoded output which also serves as the history for decoding references.
+// Literals and matches are appended directly to one buffer. Once it fills up, everything not
+// yet written is passed to the writer in one call and only the last `history_size` bytes are
+// kept at the start of the buffer.
+pub struct OutputWindow<W: Write> {
+    writer: W,
+    // Decoded bytes are buffer[0..end], the rest is room for more
+    buffer: Vec<u8>,
+    end: usize,
+    history_size: usize,
+    // Bytes in buffer[0..written] were already passed to the writer
+    written: usize,
+}
+
+impl<W: Write> OutputWindow<W> {
+    // History starts with `preset_history`, which is not written out
+    pub fn with_history(writer: W, history_size: usize, preset_history: &[u8]) -> OutputWindow<W> {
+        let preset = &preset_history[preset_history.len().saturating_sub(history_size)..];
+        let mut buffer = vec![0; history_size + FLUSH_SIZE];
+        buffer[..preset.len()].copy_from_slice(preset);
+        OutputWindow {
+            writer,
+            buffer,
+            end: preset.len(),
+            history_size,
+            written: preset.len(),
+        }
+    }
+
+    #[inline]
+    pub fn history_len(&self) -> usize {
+        usize::min(self.end, self.history_size)
+    }
+
+    #[inline]
+    pub fn push(&mut self, byte: u8) -> Result<()> {
+        self.reserve(1)?;
+        self.buffer[self.end] = byte;
+        self.end += 1;
+        Ok(())
+    }
+
+    // Appends `length` bytes starting at `position` in current history.
+    // Match may run past the end of history, in which case it repeats the bytes it has just copied.
+    #[inline]
+    pub fn copy_match(&mut self, position: usize, length: usize) -> Result<()> {
+        if position >= self.history_len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Invalid record: position bigger than current history size",
+            ));
+        }
+        self.reserve(length + COPY_SLACK)?;
+        let start = self.end - self.history_len() + position;
+        copy_match_within(&mut self.buffer, start, self.end, length);
+        self.end += length;
+        Ok(())
+    }
+
+    // Lets `decode` append to the window directly. It gets the buffer and the end of decoded
+    // bytes in it, with at least `room` bytes free after them, and has to move the end past
+    // whatever it adds. It is called again with more room for as long as it returns true.
+    pub fn decode_with<F>(&mut self, room: usize, mut decode: F) -> Result<()>
+    where
+        F: FnMut(&mut [u8], &mut usize) -> Result<bool>,
+    {
+        loop {
+            self.reserve(room)?;
+            if !decode(&mut self.buffer, &mut self.end)? {
+                return Ok(());
+            }
+        }
+    }
+
+    // Writes out everything decoded so far and flushes the writer
+    pub fn flush(&mut self) -> Result<()> {
+        self.write_pending()?;
+        self.writer.flush()
+    }
+
+    fn write_pending(&mut self) -> Result<()> {
+        self.writer
+            .write_all(&self.buffer[self.written..self.end])?;
+        self.written = self.end;
+        Ok(())
+    }
+
+    fn reserve(&mut self, additional: usize) -> Result<()> {
+        if self.end + additional > self.buffer.len() {
+            self.write_pending()?;
+            let drop = self.end - self.history_len();
+            self.buffer.copy_within(drop..self.end, 0);
+            self.end -= drop;
+            self.written = self.end;
+            if self.end + additional > self.buffer.len() {
+                self.buffer.resize(self.end + additional, 0);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Appends `length` bytes starting at `start` to buffer[0..end]. If they overlap the bytes being
+// appended, those repeat. Up to `COPY_SLACK` bytes after the match may be overwritten, so that
+// most matches are copied in one or two fixed size chunks.
+#[inline(always)]
+pub fn copy_match_within(buffer: &mut [u8], start: usize, end: usize, length: usize) {
+    let distance = end - start;
+    if distance >= 16 {
+        copy_chunks::<16>(buffer, start, end, length);
+    } else if distance >= 8 {
+        copy_chunks::<8>(buffer, start, end, length);
+    } else {
+        for i in 0..length {
+            buffer[end + i] = buffer[start + i];
+        }
+    }
+}
+
+// Chunks of `N` bytes never overlap the ones they are copied to if `N` is not bigger than the
+// distance of the match
+#[inline(always)]
+fn copy_chunks<const N: usize>(buffer: &mut [u8], start: usize, end: usize, length: usize) {
+    copy_chunk::<N>(buffer, start, end);
+    // Most matches fit in one chunk
+    let mut offset = N;
+    while offset < length {
+        copy_chunk::<N>(buffer, start + offset, end + offset);
+        offset += N;
+    }
+}
+
+#[inline(always)]
+fn copy_chunk<const N: usize>(buffer: &mut [u8], from: usize, to: usize) {
+    let mut chunk = [0; N];
+    chunk.copy_from_slice(&buffer[from..from + N]);
+    buffer[to..to + N].copy_from_slice(&chunk);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn overlapping_match_repeats_bytes() {
+        let mut out = Vec::new();
+        {
+            let mut window = OutputWindow::with_history(&mut out, 4, &[]);
+            for byte in b"xab" {
+                window.push(*byte).unwrap();
+            }
+            window.copy_match(1, 7).unwrap();
+            assert_eq!(window.history_len(), 4);
+            assert!(window.copy_match(4, 1).is_err());
+            window.flush().unwrap();
+        }
+        assert_eq!(out, b"xababababa");
+    }
+
+    #[test]
+    fn long_matches_are_copied_in_chunks() {
+        let mut buffer = b"0123456789".to_vec();
+        buffer.resize(40, 0);
+        copy_match_within(&mut buffer, 1, 10, 21);
+        assert_eq!(&buffer[..31], b"0123456789123456789123456789123");
+    }
+}
@@ -1,5 +1,5 @@
-use std::cmp;
 use log::debug;
+use std::cmp;
 
 // Find first byte string in sequence matching subsequence or beginning of subsequence
 // Returns the position of the first match and length of a matching byte string.
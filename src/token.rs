@@ -0,0 +1,175 @@
+// Record level view of an LZSS stream.
+// Tokens can be produced from raw data (`tokenize`), read from an encoded stream
+// (`parse_tokens`) and encoded again (`Encoder::encode_tokens`), so parses can be analysed or
+// transformed without dealing with the bit format.
+use super::decoder::TokenReader;
+use super::match_finder::{LinearSearch, MatchFinder};
+use super::Config;
+use std::io::{Read, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token {
+    Literal(u8),
+    // Copy of `length` bytes starting at `position` in current history
+    Match { position: usize, length: usize },
+}
+
+impl Token {
+    // Number of bytes this token expands to
+    pub fn decoded_len(&self) -> usize {
+        match *self {
+            Token::Literal(_) => 1,
+            Token::Match { length, .. } => length,
+        }
+    }
+}
+
+// Chooses a token for the beginning of window
+pub(crate) fn next_token(
+    finder: &mut dyn MatchFinder,
+    history: &[u8],
+    window: &[u8],
+    threshold: usize,
+) -> Token {
+    let (position, length) = finder.find_match(history, window, threshold);
+    if length > 0 {
+        Token::Match { position, length }
+    } else {
+        Token::Literal(window[0])
+    }
+}
+
+// Iterator over tokens the encoder would produce for a byte slice
+pub struct Tokens<'a> {
+    data: &'a [u8],
+    config: Config,
+    finder: Box<dyn MatchFinder + 'a>,
+    pos: usize,
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        if self.pos >= self.data.len() {
+            return None;
+        }
+        let window_size = self.config.current_window_size();
+        let token = if self.pos < window_size {
+            // Beginning of a stream is stored raw
+            self.finder.feed(&self.data[self.pos..=self.pos]);
+            Token::Literal(self.data[self.pos])
+        } else {
+            let history_start = self.pos.saturating_sub(self.config.history_size());
+            let window_end = usize::min(self.pos + window_size, self.data.len());
+            let history = &self.data[history_start..self.pos];
+            let window = &self.data[self.pos..window_end];
+            let token = next_token(&mut *self.finder, history, window, self.config.threshold());
+            self.finder.skip(history, window, token.decoded_len());
+            token
+        };
+        self.pos += token.decoded_len();
+        Some(token)
+    }
+}
+
+pub fn tokenize<'a>(data: &'a [u8], config: &Config) -> Tokens<'a> {
+    let finder = LinearSearch::new(config.search_depth() as usize);
+    tokenize_with(data, config, Box::new(finder))
+}
+
+// Like `tokenize`, with matches found by `finder` (see `Encoder::with_match_finder`)
+pub fn tokenize_with<'a>(
+    data: &'a [u8],
+    config: &Config,
+    mut finder: Box<dyn MatchFinder + 'a>,
+) -> Tokens<'a> {
+    finder.reset();
+    Tokens {
+        data,
+        config: *config,
+        finder,
+        pos: 0,
+    }
+}
+
+// Iterator over tokens of an encoded stream.
+// Header is read with the first call to `next`, so its errors are returned from there.
+pub struct ParsedTokens<R: Read> {
+    reader: Option<R>,
+    tokens: Option<TokenReader<R>>,
+    done: bool,
+}
+
+impl<R: Read> ParsedTokens<R> {
+    // Configuration of the stream, once header is read
+    pub fn config(&self) -> Option<&Config> {
+        self.tokens.as_ref().map(|tokens| tokens.config())
+    }
+}
+
+impl<R: Read> Iterator for ParsedTokens<R> {
+    type Item = Result<Token>;
+
+    fn next(&mut self) -> Option<Result<Token>> {
+        if let Some(reader) = self.reader.take() {
+            match TokenReader::new(reader) {
+                Ok(tokens) => self.tokens = Some(tokens),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        if self.done {
+            return None;
+        }
+        let res = self.tokens.as_mut()?.read_token();
+        match res {
+            Ok(Some(token)) => Some(Ok(token)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                // Stream can't be parsed any further
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+pub fn parse_tokens<R: Read>(reader: R) -> ParsedTokens<R> {
+    ParsedTokens {
+        reader: Some(reader),
+        tokens: None,
+        done: false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encoder::Encoder;
+
+    #[test]
+    fn parsed_tokens_match_encoder_input() {
+        let data = std::fs::read("test-files/alice29.txt").unwrap();
+        let data = &data[0..20_000];
+        let config = Config::new(10, 4, 0);
+
+        let tokens: Vec<Token> = tokenize(data, &config).collect();
+        let mut archive = Vec::new();
+        Encoder::with_config(config)
+            .encode(&mut &data[..], &mut archive)
+            .unwrap();
+        let parsed: Vec<Token> = parse_tokens(archive.as_slice())
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(parsed, tokens);
+
+        let mut reencoded = Vec::new();
+        Encoder::with_config(config)
+            .encode_tokens(tokens, &mut reencoded)
+            .unwrap();
+        assert_eq!(reencoded, archive);
+    }
+}
@@ -0,0 +1,68 @@
+// Automatic choice of history and match length sizes.
+// A sample from the beginning of input is encoded with every reasonable combination and the one
+// giving the smallest output wins. Sizes are stored in the header as usual, so decoding doesn't
+// need to know how they were chosen.
+use super::encoder::Encoder;
+use super::match_finder::HashChain;
+use super::*;
+
+// How much of the input is trial encoded
+pub const SAMPLE_SIZE: usize = 64 * 1024;
+const MIN_TRIAL_HISTORY_ADDR_BITS: u8 = 8;
+const MAX_TRIAL_HISTORY_ADDR_BITS: u8 = 16;
+const MAX_TRIAL_MATCH_LENGTH_BITS: u8 = 6;
+// Candidates checked per position in trials. Much faster than the linear search, which would
+// take longer than encoding the whole input.
+const TRIAL_MAX_CHAIN: usize = 64;
+
+// Returns `base` (search depth and layout are kept) with history and match length sizes which
+// encode `sample` into the fewest bytes. `base` is returned as is if no combination can encode
+// the sample (it's shorter than any window).
+pub fn choose_config(sample: &[u8], base: Config) -> Config {
+    // History longer than the sample is not going to be used
+    let mut max_history_addr_nbits = MIN_TRIAL_HISTORY_ADDR_BITS;
+    while max_history_addr_nbits < MAX_TRIAL_HISTORY_ADDR_BITS
+        && (1usize << max_history_addr_nbits) < sample.len()
+    {
+        max_history_addr_nbits += 1;
+    }
+
+    let mut best: Option<(usize, Config)> = None;
+    for history_addr_nbits in MIN_TRIAL_HISTORY_ADDR_BITS..=max_history_addr_nbits {
+        for match_length_nbits in MIN_MATCH_LENGTH_BITS as u8..=MAX_TRIAL_MATCH_LENGTH_BITS {
+            let config = Config::new(history_addr_nbits, match_length_nbits, base.search_depth())
+                .with_layout(base.layout());
+            let finder = HashChain::new(config.history_size(), TRIAL_MAX_CHAIN);
+            let mut output = Vec::new();
+            let res = Encoder::with_match_finder(config, Box::new(finder))
+                .encode(&mut &sample[..], &mut output);
+            if res.is_ok() && best.is_none_or(|(size, _)| output.len() < size) {
+                best = Some((output.len(), config));
+            }
+        }
+    }
+    best.map_or(base, |(_, config)| config)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chosen_config_beats_defaults() {
+        let data = std::fs::read("test-files/kennedy.xls").unwrap();
+        let sample = &data[..16 * 1024];
+        let default = Config::new(12, 4, 0);
+        let chosen = choose_config(sample, default);
+
+        let encoded_size = |config: Config| {
+            let mut output = Vec::new();
+            Encoder::with_config(config)
+                .encode(&mut &sample[..], &mut output)
+                .unwrap();
+            output.len()
+        };
+        assert_ne!(chosen, default);
+        assert!(encoded_size(chosen) < encoded_size(default));
+    }
+}
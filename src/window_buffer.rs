@@ -0,0 +1,127 @@
+use std::ops::{Deref, DerefMut};
+
+// Contiguous byte queue used for history windows.
+// Bytes live in a linear buffer twice the size of the maximum length. Dropping from the front
+// only moves the start index; once there is no room left at the back, the remaining bytes are
+// moved to the beginning of the buffer. This keeps the contents available as a single slice
+// without relying on virtual memory tricks, while each byte is moved at most once per
+// `capacity` bytes pushed.
+pub struct WindowBuffer {
+    buffer: Vec<u8>,
+    start: usize,
+    end: usize,
+    capacity: usize,
+}
+
+impl WindowBuffer {
+    pub fn with_capacity(capacity: usize) -> WindowBuffer {
+        WindowBuffer {
+            buffer: vec![0; capacity * 2],
+            start: 0,
+            end: 0,
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buffer[self.start..self.end]
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buffer[self.start..self.end]
+    }
+
+    // Changes length of the buffer. New bytes at the back are zeroed.
+    pub fn resize(&mut self, new_len: usize) {
+        let len = self.len();
+        if new_len <= len {
+            self.truncate(new_len);
+        } else {
+            self.make_room(new_len - len);
+            let new_end = self.start + new_len;
+            for byte in &mut self.buffer[self.end..new_end] {
+                *byte = 0;
+            }
+            self.end = new_end;
+        }
+    }
+
+    // Removes bytes from the back, so that only `len` bytes remain
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.len() {
+            self.end = self.start + len;
+        }
+    }
+
+    // Removes `n` bytes from the front
+    pub fn drop_front(&mut self, n: usize) {
+        assert!(
+            n <= self.len(),
+            "Cannot drop more bytes than there are in the buffer"
+        );
+        self.start += n;
+        if self.is_empty() {
+            self.start = 0;
+            self.end = 0;
+        }
+    }
+
+    fn make_room(&mut self, additional: usize) {
+        assert!(
+            self.len() + additional <= self.capacity,
+            "Window buffer capacity exceeded"
+        );
+        if self.end + additional > self.buffer.len() {
+            self.buffer.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+    }
+}
+
+impl Deref for WindowBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl DerefMut for WindowBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.as_mut_slice()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn behaves_like_a_queue() {
+        let mut buffer = WindowBuffer::with_capacity(4);
+        let mut expected: Vec<u8> = Vec::new();
+        for i in 0..64u8 {
+            if buffer.len() == 4 {
+                buffer.drop_front(3);
+                expected.drain(0..3);
+            }
+            let len = buffer.len();
+            buffer.resize(len + 1);
+            buffer[len] = i;
+            expected.push(i);
+            assert_eq!(buffer.as_slice(), expected.as_slice());
+        }
+        buffer.resize(4);
+        buffer.truncate(2);
+        assert_eq!(&buffer[..], &expected[0..2]);
+    }
+}